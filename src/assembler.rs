@@ -1,19 +1,56 @@
-use std::{collections::HashMap, fs::File, error::Error};
+use std::{collections::{HashMap, HashSet}, fs::File, error::Error, path::{Path, PathBuf}, rc::Rc};
 
-use colored::Colorize;
+use colored::{Color, Colorize};
 
-use crate::{instruction::Instruction, debug_info::DebugInfo};
+use crate::{instruction::Instruction, debug_info::DebugInfo, binary::Binary, expr::{BinOp, EvalError, Expr}, lint::{Lint, LintInput, Warning}, listing::{self, ListingLine}, object::{Location, Object, Relocation, Symbol}};
 
 use crate::instruction::*;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
+
+/// A range of characters within a single source line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub file: String,
+    pub lineno: usize,
+    /// 1-based column of the first character
+    pub col: usize,
+    pub len: usize,
+    /// text of the whole source line
+    pub line: String,
+}
+
+impl Span {
+    // renders a rustc-style snippet with carets under the spanned characters
+    pub(crate) fn fmt_snippet(&self, f: &mut std::fmt::Formatter<'_>, color: Color) -> std::fmt::Result {
+        let lineno = self.lineno.to_string();
+        let gutter = " ".repeat(lineno.len());
+        let bar = "|".blue().bold();
+
+        writeln!(f, "{}{} {}:{}:{}", gutter, "-->".blue().bold(), self.file, self.lineno, self.col)?;
+        writeln!(f, "{} {}", gutter, bar)?;
+        writeln!(f, "{} {} {}", lineno.blue().bold(), bar, self.line)?;
+
+        // keep tabs so that the carets line up with the source line
+        let padding: String = self.line.chars()
+            .take(self.col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "{} {} {}{}", gutter, bar, padding, "^".repeat(self.len.max(1)).color(color).bold())
+    }
+}
+
+/// Secondary messages pointing at related source locations.
+pub type Notes = Vec<(String, Span)>;
 
 #[derive(Debug)]
 pub enum ParseError {
     Parse {
         err: String,
-        file: String,
-        lineno: usize,
+        span: Span,
+        notes: Notes,
     },
+    /// a lint warning while warnings are treated as errors
+    Denied(Box<Warning>),
     Io(std::io::Error)
 }
 
@@ -26,13 +63,16 @@ impl From<std::io::Error> for ParseError {
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Parse { err, file, lineno } => write!(f, 
-                "{} {}:{}: {}", 
-                "Parse Error:".bold().red(), 
-                file.bold(),
-                lineno.to_string().bold(),
-                err
-            ),
+            Self::Parse { err, span, notes } => {
+                writeln!(f, "{} {}", "Parse Error:".bold().red(), err)?;
+                span.fmt_snippet(f, Color::Red)?;
+                for (note, span) in notes {
+                    writeln!(f, "\n{} {}", "note:".bold().cyan(), note)?;
+                    span.fmt_snippet(f, Color::Red)?;
+                }
+                Ok(())
+            }
+            Self::Denied(warning) => warning.fmt_denied(f),
             Self::Io(err) => write!(f, "{} {}", "Io Error:".bold().red(), err),
         }
     }
@@ -42,185 +82,1646 @@ impl Error for ParseError {}
 
 pub type ParseResult<T> = Result<T, ParseError>;
 
+/// All errors found while assembling a program.
+#[derive(Debug)]
+pub struct ParseErrors(pub Vec<ParseError>);
+
+impl From<ParseError> for ParseErrors {
+    fn from(value: ParseError) -> Self {
+        Self(vec![value])
+    }
+}
+
+impl std::fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for err in &self.0 {
+            writeln!(f, "{}\n", err)?;
+        }
+        write!(f, "{} could not assemble due to {} error{}",
+            "Error:".bold().red(),
+            self.0.len(),
+            if self.0.len() == 1 { "" } else { "s" }
+        )
+    }
+}
+
+impl Error for ParseErrors {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    Word,
+    Str,
+    /// character literal in single quotes
+    Char,
+    Comma,
+    /// single-character operator or parenthesis
+    Op,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    /// 1-based column of the first character
+    col: usize,
+    /// length in characters. Tokens substituted into a macro body keep
+    /// the position of the parameter they replace.
+    len: usize,
+}
+
+struct MacroLine {
+    lineno: usize,
+    line: String,
+    tokens: Vec<Token>,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<MacroLine>,
+    /// labels defined in the body, made unique for every expansion
+    labels: HashSet<String>,
+    file: String,
+    span: Span,
+    // parse order of the `@Macro` line
+    seq: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockKind {
+    If,
+    While,
+    Loop,
+}
+
+impl BlockKind {
+    fn directive(&self) -> &'static str {
+        match self {
+            Self::If => "@If",
+            Self::While => "@While",
+            Self::Loop => "@Loop",
+        }
+    }
+}
+
+// an open `@If`, `@While` or `@Loop` block
+struct Block {
+    kind: BlockKind,
+    id: usize,
+    span: Span,
+    has_else: bool,
+    // parse order of the opening line
+    seq: usize,
+}
+
+impl Block {
+    // hidden label marking `part` of the block. Names starting with `@`
+    // can't be written by users, so they never collide.
+    fn label(&self, part: &str) -> String {
+        format!("{}#{}.{}", self.kind.directive(), self.id, part)
+    }
+}
+
+// whether `label` is visible outside of its module, as opposed to local
+// labels, labels generated by macros and hidden block labels
+fn is_global_label(label: &str) -> bool {
+    !label.contains(['.', '#']) && !label.starts_with('@')
+}
+
+// names of built-in metainstructions, which macros may not shadow
+const METAINSTRUCTIONS: [&str; 18] = [
+    "PushStr", "String", "Data", "Syscall", "Break", "Macro", "EndMacro", "Include", "Define",
+    "If", "Else", "EndIf", "While", "EndWhile", "Loop", "EndLoop", "BreakLoop", "ContinueLoop"
+];
+
+const OPERATORS: [char; 7] = ['+', '-', '*', '/', '%', '(', ')'];
+
+
+// an expression that could not be evaluated yet because `span` refers to an undefined name
+struct Reloc {
+    target: Location,
+    expr: Expr,
+    span: Span,
+    notes: Notes,
+    // parse order of the line using the name
+    seq: usize,
+}
+
+// a macro expansion or included file that the parser is currently inside of
+enum Expansion {
+    Macro(String),
+    Include,
+}
+
 pub struct AsmParser {
     filepath: String,
+    source: Option<String>,
     lineno: usize,
+    line: String,
+    // errors with the parse order of the line they belong to
+    errors: Vec<(usize, ParseError)>,
+    // number of lines parsed so far, counting the lines of included files and
+    // macro bodies where they are parsed, so that errors are in source order
+    seq: usize,
 
     labels: HashMap<String, i64>,
-    relocs: HashMap<String, Vec<i64>>,
+    label_defs: HashMap<String, Span>,
+    // last global label, which local `.labels` belong to
+    scope: Option<String>,
+    constants: HashMap<String, (Expr, Span)>,
+    // pending expressions, keyed by the undefined name they are waiting for
+    relocs: HashMap<String, Vec<Reloc>>,
+
+    macros: HashMap<String, Rc<Macro>>,
+    // name and body of the macro currently being defined
+    macro_def: Option<(String, Macro)>,
+    // every macro expansion and include currently being parsed and where
+    // it was started from, innermost last
+    expansions: Vec<(Expansion, Span)>,
+    num_expansions: usize,
+
+    // open control flow blocks, innermost last
+    blocks: Vec<Block>,
+    num_blocks: usize,
+
+    include_dirs: Vec<PathBuf>,
+    // canonical paths of the files currently being parsed, for cycle detection
+    include_stack: Vec<PathBuf>,
+
+    data: Vec<u8>,
+    debug_info: DebugInfo,
+
+    // collected for the lint pass
+    instruction_spans: Vec<Span>,
+    used_names: HashSet<String>,
+    breakpoints: Vec<Span>,
+    allowed: HashMap<(String, usize), HashSet<Lint>>,
+    warnings: Vec<Warning>,
+    deny_warnings: bool,
+
+    listing: Vec<ListingLine>,
 
-    debug_info: DebugInfo
+    // whether undefined global labels are left for the linker
+    object: bool,
+    // every expression referring to names, with where its value goes
+    object_relocs: Vec<(Location, Expr)>,
+    data_labels: HashSet<String>,
 }
 
 impl AsmParser {
+    /// Creates a parser for the file at `filepath`, which is read by `assemble`.
     pub fn new(filepath: String) -> Self {
         Self {
             filepath,
+            source: None,
             lineno: 0,
+            line: String::new(),
+            errors: vec![],
+            seq: 0,
             labels: HashMap::new(),
+            label_defs: HashMap::new(),
+            scope: None,
+            constants: HashMap::new(),
             relocs: HashMap::new(),
-            debug_info: DebugInfo::default()
+            macros: HashMap::new(),
+            macro_def: None,
+            expansions: vec![],
+            num_expansions: 0,
+            blocks: vec![],
+            num_blocks: 0,
+            include_dirs: vec![],
+            include_stack: vec![],
+            data: vec![],
+            debug_info: DebugInfo::default(),
+            instruction_spans: vec![],
+            used_names: HashSet::new(),
+            breakpoints: vec![],
+            allowed: HashMap::new(),
+            warnings: vec![],
+            deny_warnings: false,
+            listing: vec![],
+            object: false,
+            object_relocs: vec![],
+            data_labels: HashSet::new(),
         }
     }
 
-    pub fn assemble(&mut self) -> ParseResult<Vec<Instruction>> {
-        let file = File::open(self.filepath.clone())?;
-        let lines = BufReader::new(file).lines().enumerate();
+    /// Creates a parser for in-memory source code. `name` takes the place of
+    /// the file path in diagnostics.
+    pub fn from_source(name: String, source: String) -> Self {
+        Self {
+            source: Some(source),
+            ..Self::new(name)
+        }
+    }
+
+    pub fn assemble(&mut self) -> Result<Binary, ParseErrors> {
+        match self.source.take() {
+            Some(source) => self.assemble_reader(source.as_bytes()),
+            None => {
+                let file = File::open(self.filepath.clone()).map_err(ParseError::from)?;
+                if let Ok(path) = Path::new(&self.filepath).canonicalize() {
+                    self.include_stack.push(path);
+                }
+                self.assemble_reader(BufReader::new(file))
+            }
+        }
+    }
+
+    /// Assembles the file into an object file. Global labels it doesn't define
+    /// are left to be resolved by `link`.
+    pub fn assemble_object(&mut self) -> Result<Object, ParseErrors> {
+        self.object = true;
+        let binary = self.assemble()?;
+        Ok(self.make_object(binary))
+    }
+
+    /// Like `assemble_object`, reading the source code from `reader`.
+    pub fn assemble_object_reader(&mut self, reader: impl BufRead) -> Result<Object, ParseErrors> {
+        self.object = true;
+        let binary = self.assemble_reader(reader)?;
+        Ok(self.make_object(binary))
+    }
+
+    fn make_object(&self, binary: Binary) -> Object {
+        let mut symbols: Vec<_> = self.labels.iter()
+            .map(|(name, value)| Symbol {
+                name: name.clone(),
+                location: if self.data_labels.contains(name) {
+                    Location::Data(*value as usize)
+                } else {
+                    Location::Instruction(*value as usize)
+                },
+                exported: is_global_label(name)
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+
+        // expressions of constants only are final already
+        let relocs = self.object_relocs.iter()
+            .map(|(location, expr)| Relocation { location: *location, expr: expr.expand_constants(&self.constants) })
+            .filter(|reloc| {
+                let mut names = false;
+                reloc.expr.visit_names(&mut |_, _| names = true);
+                names
+            })
+            .collect();
+
+        Object::new(self.filepath.clone(), binary.instructions().to_vec(), binary.data().to_vec(), symbols, relocs)
+    }
+
+    /// Makes lint warnings fail the assembly instead of being returned by `warnings`.
+    pub fn set_deny_warnings(&mut self, deny_warnings: bool) {
+        self.deny_warnings = deny_warnings;
+    }
+
+    /// Lint warnings found by the last successful assembly.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Writes a listing of `binary`, which must be the result of the last
+    /// assembly, showing the address, encoded bytes and label of every source line.
+    pub fn write_listing(&self, binary: &Binary, out: &mut dyn Write) -> std::io::Result<()> {
+        listing::write_listing(&self.listing, binary, out)
+    }
+
+    /// Adds a directory searched by `@Include` for files that are not found
+    /// relative to the including file.
+    pub fn add_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_dirs.push(dir.into());
+    }
+
+    /// Assembles the source code read from `reader`, reporting diagnostics
+    /// under the parser's file name. Parsing continues after errors so that
+    /// all of them are reported at once.
+    pub fn assemble_reader(&mut self, reader: impl BufRead) -> Result<Binary, ParseErrors> {
         let mut instructions = vec![];
+        self.parse_lines(reader, &mut instructions);
 
-        for (lineno, line) in lines {
+        // report unresolved names at every use, objects leave global ones to the linker
+        let object = self.object;
+        self.relocs.retain(|name, _| !(object && is_global_label(name)));
+        let unresolved: Vec<_> = self.relocs.drain()
+            .flat_map(|(name, relocs)| relocs.into_iter().map(move |reloc| (name.clone(), reloc)))
+            .collect();
+        self.errors.extend(unresolved.into_iter().map(|(name, reloc)| (reloc.seq, ParseError::Parse {
+            err: format!("could not resolve label or constant `{}`", name),
+            span: reloc.span,
+            notes: reloc.notes
+        })));
+
+        if self.errors.is_empty() {
+            if self.object {
+                // other modules may refer to global labels
+                let globals = self.labels.keys().filter(|label| is_global_label(label)).cloned();
+                self.used_names.extend(globals.collect::<Vec<_>>());
+            }
+
+            let warnings = LintInput {
+                instructions: &instructions,
+                instruction_spans: &self.instruction_spans,
+                label_defs: &self.label_defs,
+                used_names: &self.used_names,
+                breakpoints: &self.breakpoints,
+                allowed: &self.allowed,
+                debug_info: &self.debug_info
+            }.run();
+
+            if self.deny_warnings && !warnings.is_empty() {
+                return Err(ParseErrors(warnings.into_iter().map(|warning| ParseError::Denied(Box::new(warning))).collect()))
+            }
+            self.warnings = warnings;
+            Ok(Binary::new(instructions, std::mem::take(&mut self.data)))
+        }
+        else {
+            let mut errors = std::mem::take(&mut self.errors);
+            errors.sort_by_key(|(seq, _)| *seq);
+            Err(ParseErrors(errors.into_iter().map(|(_, err)| err).collect()))
+        }
+    }
+
+    fn parse_lines(&mut self, reader: impl BufRead, instructions: &mut Vec<Instruction>) {
+        let open_blocks = self.blocks.len();
+        for (lineno, line) in reader.lines().enumerate() {
+            self.seq += 1;
             self.lineno = lineno + 1;
-            if let Some(instruction) = self.parse_line(&line?, &mut instructions)? {
-                instructions.extend(instruction);
+            self.line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    self.report(err.into());
+                    break
+                }
+            };
+
+            let entry = self.begin_listing(instructions.len());
+            if let Err(err) = self.parse_line(instructions) {
+                self.report(err);
             }
+            self.end_listing(entry, instructions.len());
         }
 
-        self.relocs.is_empty()
-            .then_some(instructions)
-            .ok_or_else(|| self.parse_error(format!("could not resolve labels {:?}", self.relocs)))
+        if let Some((name, def)) = self.macro_def.take() {
+            self.errors.push((def.seq, self.error_at(def.span, format!("macro `{}` is missing `@EndMacro`", name))));
+        }
+        for block in self.blocks.split_off(open_blocks.min(self.blocks.len())) {
+            // drop the jumps out of the block so that they aren't reported as well
+            self.relocs.remove(&block.label("else"));
+            self.relocs.remove(&block.label("end"));
+            let closing = match block.kind {
+                BlockKind::If => "@EndIf",
+                BlockKind::While => "@EndWhile",
+                BlockKind::Loop => "@EndLoop",
+            };
+            self.errors.push((block.seq, self.error_at(block.span, format!("`{}` is missing `{}`", block.kind.directive(), closing))));
+        }
+    }
+
+    fn report(&mut self, err: ParseError) {
+        self.errors.push((self.seq, err));
+    }
+
+    // starts the listing entry of the current line
+    fn begin_listing(&mut self, code_addr: usize) -> usize {
+        self.listing.push(ListingLine {
+            file: self.filepath.clone(),
+            lineno: self.lineno,
+            line: self.line.clone(),
+            label: None,
+            addr: code_addr,
+            code: code_addr..code_addr,
+            data: self.data.len()..self.data.len()
+        });
+        self.listing.len() - 1
+    }
+
+    // assigns the code and data produced since `begin_listing` to the entry,
+    // except for what the lines of included files claimed
+    fn end_listing(&mut self, entry: usize, code_end: usize) {
+        let (code_start, data_start) = match self.listing[entry + 1..].last() {
+            Some(included) => (included.code.end, included.data.end),
+            None => (self.listing[entry].code.start, self.listing[entry].data.start)
+        };
+        let data_end = self.data.len();
+        let line = &mut self.listing[entry];
+        line.code = code_start..code_end;
+        line.data = data_start..data_end;
     }
 
     pub fn debug_info(self) -> DebugInfo {
         self.debug_info
     }
 
-    fn parse_error(&self, err: String) -> ParseError {
+    fn span(&self, col: usize, len: usize) -> Span {
+        Span {
+            file: self.filepath.clone(),
+            lineno: self.lineno,
+            col,
+            len,
+            line: self.line.clone()
+        }
+    }
+
+    fn token_span(&self, token: &Token) -> Span {
+        self.span(token.col, token.len)
+    }
+
+    // points at the macro call sites and includes leading to the current line
+    fn expansion_notes(&self) -> Notes {
+        self.expansions.iter()
+            .rev()
+            .map(|(expansion, span)| match expansion {
+                Expansion::Macro(name) => (format!("in expansion of macro `{}`", name), span.clone()),
+                Expansion::Include => ("included from here".to_string(), span.clone())
+            })
+            .collect()
+    }
+
+    // temporarily moves the parser to another file, returning the old location
+    fn enter(&mut self, expansion: Expansion, span: Span, filepath: String) -> (String, usize, String) {
+        self.expansions.push((expansion, span));
+        let filepath = std::mem::replace(&mut self.filepath, filepath);
+        (filepath, self.lineno, std::mem::take(&mut self.line))
+    }
+
+    fn leave(&mut self, (filepath, lineno, line): (String, usize, String)) {
+        self.expansions.pop();
+        self.filepath = filepath;
+        self.lineno = lineno;
+        self.line = line;
+    }
+
+    fn error_at(&self, span: Span, err: String) -> ParseError {
         ParseError::Parse {
             err,
-            file: self.filepath.clone(),
-            lineno: self.lineno
+            span,
+            notes: self.expansion_notes()
         }
     }
 
-    fn label_addr(&mut self, label: String, instruction_addr: i64) -> i64 {
-        *self.labels.get(&label).unwrap_or_else(|| {
-            if self.relocs.get_mut(&label).map(|rel| rel.push(instruction_addr)).is_none() {
-                self.relocs.insert(label, vec![instruction_addr]);
+    fn with_note(err: ParseError, note: String, span: Span) -> ParseError {
+        match err {
+            ParseError::Parse { err, span: primary, mut notes } => {
+                notes.insert(0, (note, span));
+                ParseError::Parse { err, span: primary, notes }
             }
-            &0
-        })
+            err => err
+        }
     }
 
-    fn parse_instruction(&mut self, mnemonic: &str, arg: Option<String>, instruction_addr: i64) -> ParseResult<Instruction> {
-        use Instruction as I;
+    fn token_error(&self, token: &Token, err: String) -> ParseError {
+        self.error_at(self.token_span(token), err)
+    }
+
+    fn tokenize(&self) -> ParseResult<Vec<Token>> {
+        let chars: Vec<char> = self.line.chars().collect();
+        let mut tokens = vec![];
+
+        let mut i = 0;
+        while i < chars.len() {
+            let start = i;
+            let kind = match chars[i] {
+                c if c.is_whitespace() => {
+                    i += 1;
+                    continue
+                }
+                ';' => break,
+                ',' => {
+                    i += 1;
+                    TokenKind::Comma
+                }
+                quote @ ('"' | '\'') => {
+                    let (kind, what) = if quote == '"' { (TokenKind::Str, "string") } else { (TokenKind::Char, "character") };
+                    i += 1;
+                    loop {
+                        match chars.get(i) {
+                            None => return Err(self.error_at(
+                                self.span(start + 1, chars.len() - start),
+                                format!("unterminated {} literal", what)
+                            )),
+                            Some('\\') => i += 2,
+                            Some(c) if *c == quote => break,
+                            Some(_) => i += 1
+                        }
+                    }
+                    i += 1;
+                    kind
+                }
+                c if OPERATORS.contains(&c) => {
+                    i += 1;
+                    TokenKind::Op
+                }
+                _ => {
+                    while i < chars.len() && !chars[i].is_whitespace() && !matches!(chars[i], ',' | '"' | '\'' | ';') && !OPERATORS.contains(&chars[i]) {
+                        i += 1;
+                    }
+                    TokenKind::Word
+                }
+            };
+
+            tokens.push(Token {
+                kind,
+                text: chars[start..i].iter().collect(),
+                col: start + 1,
+                len: i - start
+            });
+        }
+
+        Ok(tokens)
+    }
+
+    // evaluates `expr` if possible, otherwise defers it until the missing name gets defined
+    fn eval_or_defer(&mut self, expr: Expr, target: Location) -> ParseResult<Value> {
+        self.mark_used(&expr);
+        if self.object {
+            let mut names = false;
+            expr.visit_names(&mut |_, _| names = true);
+            if names {
+                self.object_relocs.push((target, expr.clone()));
+            }
+        }
+        match expr.eval(&self.constants, &self.labels) {
+            Ok(value) => Ok(value),
+            Err(EvalError::Unresolved(name, span)) => {
+                let notes = self.expansion_notes();
+                self.relocs.entry(name).or_default().push(Reloc { target, expr, span, notes, seq: self.seq });
+                Ok(0)
+            }
+            Err(EvalError::Invalid(err, span)) => Err(self.error_at(span, err))
+        }
+    }
 
-        let arg = arg.map(|arg| arg.parse::<Value>().unwrap_or_else(|_| self.label_addr(arg.to_string(), instruction_addr)));
-        match mnemonic {
-            "PUSH" => arg.map(I::Push).ok_or(self.parse_error("`PUSH` expects one argument".to_string())),
-            "POP" => Ok(I::Pop),
-            "DUP" => Ok(I::Dup),
-            "SWAP" => Ok(I::Swap),
-            "JZ" => Ok(I::Jz),
-            "JNZ" => Ok(I::Jnz),
-            "JMP" => Ok(I::Jmp),
-            "CALL" => Ok(I::Call),
-            "ADD" => Ok(I::Add),
-            "SUB" => Ok(I::Sub),
-            "MUL" => Ok(I::Mul),
-            "DIV" => Ok(I::Div),
-            "EXIT" => Ok(I::Exit),
-            "PRINTOUT" => Ok(I::Printout),
-            "PRINTSTR" => Ok(I::Printstr),
-            _ => Err(self.parse_error(format!("no such mnemonic `{}`", mnemonic)))
-        }
-    }
-
-    fn escape_code(&self, code: char) -> ParseResult<char> {
+    fn mark_used(&mut self, expr: &Expr) {
+        expr.visit_names(&mut |name, _| {
+            self.used_names.insert(name.to_string());
+        });
+    }
+
+    fn patch(&mut self, target: Location, value: Value, instructions: &mut [Instruction]) {
+        match target {
+            Location::Instruction(addr) => if let Some(i) = instructions.get_mut(addr) {
+                i.set_arg(value)
+            }
+            Location::Data(offset) => if let Some(word) = self.data.get_mut(offset..offset + std::mem::size_of::<Value>()) {
+                word.copy_from_slice(&value.to_le_bytes())
+            }
+        }
+    }
+
+    // re-evaluates the expressions waiting for `name` after it got defined
+    fn resolve_relocs(&mut self, name: &str, instructions: &mut [Instruction]) {
+        for reloc in self.relocs.remove(name).unwrap_or_default() {
+            match reloc.expr.eval(&self.constants, &self.labels) {
+                Ok(value) => self.patch(reloc.target, value, instructions),
+                Err(EvalError::Unresolved(name, span)) => self.relocs.entry(name).or_default().push(Reloc { span, ..reloc }),
+                Err(EvalError::Invalid(err, span)) => self.errors.push((reloc.seq, ParseError::Parse { err, span, notes: reloc.notes }))
+            }
+        }
+    }
+
+    // qualifies local `.labels` with the current global label, e.g. `routine.loop`
+    fn qualify(&self, name: &str, span: &Span) -> ParseResult<String> {
+        if !name.starts_with('.') {
+            return Ok(name.to_string())
+        }
+
+        match &self.scope {
+            Some(scope) => Ok(format!("{}{}", scope, name)),
+            None => Err(self.error_at(span.clone(), format!("local label `{}` used before any global label", name)))
+        }
+    }
+
+    // label names can't be empty or start with a digit, which would make them integers
+    fn check_label_name(&self, name: &str, span: &Span) -> ParseResult<()> {
+        let bare = name.strip_prefix('.').unwrap_or(name);
+        if bare.is_empty() || bare.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error_at(span.clone(), format!("invalid label name `{}`", name)))
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, label: &str, span: Span, value: i64, instructions: &mut [Instruction]) -> ParseResult<()> {
+        if let Some((_, constant)) = self.constants.get(label) {
+            let err = self.error_at(span, format!("label `{}` is already defined as a constant", label));
+            return Err(Self::with_note(err, "constant defined here".to_string(), constant.clone()))
+        }
+        if let Some(previous) = self.label_defs.get(label) {
+            let err = self.error_at(span, format!("label `{}` is defined multiple times", label));
+            return Err(Self::with_note(err, "previously defined here".to_string(), previous.clone()))
+        }
+
+        self.labels.insert(label.to_string(), value);
+        self.label_defs.insert(label.to_string(), span);
+        if let Some(line) = self.listing.last_mut() {
+            line.label.get_or_insert_with(|| label.to_string());
+        }
+        self.resolve_relocs(label, instructions);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &Token, operands: &[Token], instructions: &mut [Instruction]) -> ParseResult<()> {
+        let span = self.token_span(name);
+        if let Some((_, previous)) = self.constants.get(&name.text) {
+            let err = self.error_at(span, format!("constant `{}` is defined multiple times", name.text));
+            return Err(Self::with_note(err, "previously defined here".to_string(), previous.clone()))
+        }
+        if let Some(label) = self.label_defs.get(&name.text) {
+            let err = self.error_at(span, format!("constant `{}` is already defined as a label", name.text));
+            return Err(Self::with_note(err, "label defined here".to_string(), label.clone()))
+        }
+        if name.text.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error_at(span, format!("invalid constant name `{}`", name.text)))
+        }
+
+        let expr = self.parse_expr(operands, name)?;
+
+        // constants referenced so far can't refer back to this one, so following
+        // them finds every cycle
+        let mut pending = vec![&expr];
+        while let Some(expr) = pending.pop() {
+            let mut cycle = None;
+            expr.visit_names(&mut |referenced, span| {
+                if referenced == name.text {
+                    cycle = Some(span.clone());
+                }
+                else if let Some((expr, _)) = self.constants.get(referenced) {
+                    pending.push(expr);
+                }
+            });
+            if let Some(cycle) = cycle {
+                let err = self.error_at(span, format!("constant `{}` is defined in terms of itself", name.text));
+                return Err(Self::with_note(err, "referenced here".to_string(), cycle))
+            }
+        }
+
+        self.mark_used(&expr);
+        self.constants.insert(name.text.clone(), (expr, span));
+        self.resolve_relocs(&name.text, instructions);
+        Ok(())
+    }
+
+    fn parse_expr(&self, tokens: &[Token], context: &Token) -> ParseResult<Expr> {
+        let mut pos = 0;
+        let expr = self.parse_sum(tokens, &mut pos, context)?;
+        match tokens.get(pos) {
+            Some(token) => Err(self.token_error(token, format!("unexpected `{}` in expression", token.text))),
+            None => Ok(expr)
+        }
+    }
+
+    fn parse_binary(&self, tokens: &[Token], pos: &mut usize, context: &Token, ops: &[(&str, BinOp)], operand: fn(&Self, &[Token], &mut usize, &Token) -> ParseResult<Expr>) -> ParseResult<Expr> {
+        let mut lhs = operand(self, tokens, pos, context)?;
+        while let Some((token, op)) = tokens.get(*pos)
+            .filter(|token| token.kind == TokenKind::Op)
+            .and_then(|token| ops.iter().find(|(text, _)| *text == token.text).map(|(_, op)| (token, *op))) {
+            *pos += 1;
+            let rhs = operand(self, tokens, pos, token)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs), self.token_span(token));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_sum(&self, tokens: &[Token], pos: &mut usize, context: &Token) -> ParseResult<Expr> {
+        self.parse_binary(tokens, pos, context, &[("+", BinOp::Add), ("-", BinOp::Sub)], Self::parse_product)
+    }
+
+    fn parse_product(&self, tokens: &[Token], pos: &mut usize, context: &Token) -> ParseResult<Expr> {
+        self.parse_binary(tokens, pos, context, &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Mod)], Self::parse_unary)
+    }
+
+    fn parse_unary(&self, tokens: &[Token], pos: &mut usize, context: &Token) -> ParseResult<Expr> {
+        // `context` is the token before the operand, for errors at the end of the expression
+        let token = tokens.get(*pos)
+            .ok_or_else(|| self.token_error(context, format!("expected an operand after `{}`", context.text)))?;
+        *pos += 1;
+
+        match (token.kind, token.text.as_str()) {
+            (TokenKind::Op, "-") => match tokens.get(*pos) {
+                // negate literals directly, so that the most negative value can be written
+                Some(literal) if literal.kind == TokenKind::Word && literal.text.starts_with(|c: char| c.is_ascii_digit()) => {
+                    *pos += 1;
+                    self.parse_int_lit(literal, true).map(Expr::Value)
+                }
+                _ => Ok(Expr::Neg(Box::new(self.parse_unary(tokens, pos, token)?), self.token_span(token)))
+            }
+            (TokenKind::Op, "(") => {
+                let expr = self.parse_sum(tokens, pos, token)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(expr)
+                    }
+                    _ => Err(self.token_error(token, "unclosed `(` in expression".to_string()))
+                }
+            }
+            (TokenKind::Word, _) => self.parse_atom(token),
+            (TokenKind::Char, _) => self.parse_char_lit(token).map(Expr::Value),
+            _ => Err(self.token_error(token, format!("expected an operand, found `{}`", token.text)))
+        }
+    }
+
+    fn parse_atom(&self, token: &Token) -> ParseResult<Expr> {
+        if token.text.starts_with(|c: char| c.is_ascii_digit()) {
+            self.parse_int_lit(token, false).map(Expr::Value)
+        }
+        else if token.text.starts_with('@') {
+            Err(self.token_error(token, format!("expected an operand, found `{}`", token.text)))
+        }
+        else {
+            let span = self.token_span(token);
+            Ok(Expr::Name(self.qualify(&token.text, &span)?, span))
+        }
+    }
+
+    // parses a decimal, `0x`, `0b` or `0o` integer literal with optional `_` separators;
+    // prefixed literals may use all 64 bits
+    fn parse_int_lit(&self, token: &Token, negative: bool) -> ParseResult<Value> {
+        let text = token.text.as_str();
+        let (radix, base, digits) = match text.get(..2) {
+            Some("0x" | "0X") => (16, "hexadecimal", &text[2..]),
+            Some("0b" | "0B") => (2, "binary", &text[2..]),
+            Some("0o" | "0O") => (8, "octal", &text[2..]),
+            _ => (10, "decimal", text)
+        };
+
+        let prefix_len = text.len() - digits.len();
+        if let Some((i, c)) = digits.chars().enumerate().find(|(_, c)| !c.is_digit(radix) && *c != '_') {
+            return Err(self.error_at(self.span(token.col + prefix_len + i, 1), format!("invalid digit `{}` in {} literal", c, base)))
+        }
+        if digits.chars().all(|c| c == '_') {
+            return Err(self.token_error(token, format!("missing digits in {} literal `{}`", base, text)))
+        }
+        // separators go between two digits
+        let chars: Vec<char> = digits.chars().collect();
+        let misplaced = (0..chars.len()).find(|&i| chars[i] == '_'
+            && (i == 0 || i + 1 == chars.len() || chars[i - 1] == '_' || chars[i + 1] == '_'));
+        if let Some(i) = misplaced {
+            return Err(self.error_at(self.span(token.col + prefix_len + i, 1), format!("`_` must be placed between digits in {} literal `{}`", base, text)))
+        }
+
+        let magnitude = u64::from_str_radix(&digits.replace('_', ""), radix).ok();
+        let value = match (magnitude, negative) {
+            (Some(magnitude), true) => 0i64.checked_sub_unsigned(magnitude),
+            (Some(magnitude), false) if radix == 10 => Value::try_from(magnitude).ok(),
+            (Some(magnitude), false) => Some(magnitude as Value),
+            (None, _) => None
+        };
+        value.ok_or_else(|| self.token_error(token, format!("integer literal `{}{}` is out of range", if negative { "-" } else { "" }, text)))
+    }
+
+    fn parse_char_lit(&self, token: &Token) -> ParseResult<Value> {
+        let inner: Vec<char> = token.text[1..token.text.len() - 1].chars().collect();
+        let c = match inner.as_slice() {
+            ['\\', code] => self.escape_code(*code, self.span(token.col + 1, 2))?,
+            [c] => *c,
+            [] => return Err(self.token_error(token, "empty character literal".to_string())),
+            _ => return Err(self.token_error(token, "character literal must contain exactly one character".to_string()))
+        };
+        Ok(c as Value)
+    }
+
+    fn parse_instruction(&mut self, mnemonic: &Token, operands: &[Token], instruction_addr: i64) -> ParseResult<Instruction> {
+        let instruction = match mnemonic.text.as_str() {
+            "PUSH" => {
+                if operands.is_empty() {
+                    return Err(self.token_error(mnemonic, "`PUSH` expects one argument".to_string()))
+                }
+                let expr = self.parse_expr(operands, mnemonic)?;
+                let value = self.eval_or_defer(expr, Location::Instruction(instruction_addr as usize))?;
+                return Ok(Instruction::Push(value))
+            }
+            other => Instruction::from_mnemonic(other)
+                .ok_or_else(|| self.token_error(mnemonic, format!("no such mnemonic `{}`", mnemonic.text)))?
+        };
+
+        match operands.first() {
+            Some(arg) => Err(self.token_error(arg, format!("`{}` does not take an argument", mnemonic.text))),
+            None => Ok(instruction)
+        }
+    }
+
+    fn escape_code(&self, code: char, span: Span) -> ParseResult<char> {
         match code {
             'n' => Ok('\n'),
             't' => Ok('\t'),
             '0' => Ok('\0'),
             '\\' => Ok('\\'),
-            _ => Err(self.parse_error(format!("unknown escape character `\\{}` in string literal", code)))
+            '\'' => Ok('\''),
+            '"' => Ok('"'),
+            _ => Err(self.error_at(span, format!("unknown escape character `\\{}`", code)))
         }
     }
 
-    fn parse_str_lit(&self, arg: String) -> ParseResult<Vec<char>> {
-        if !arg.starts_with('"') || !arg.ends_with('"') {
-            return Err(self.parse_error(format!("expect argument `{}` to be a string literal", arg)))
+    fn parse_str_lit(&self, arg: &Token) -> ParseResult<Vec<char>> {
+        if arg.kind != TokenKind::Str {
+            return Err(self.token_error(arg, format!("expect argument `{}` to be a string literal", arg.text)))
         }
 
-        let mut char_vec = Vec::with_capacity(arg.len() - 2);
-        let mut chars = arg.chars();
-        chars.next();
-        chars.next_back();
-        while let Some(c) = chars.next() {
-            char_vec.push(if c == '\\' { self.escape_code(chars.next().unwrap())? } else { c })
+        let mut char_vec = Vec::with_capacity(arg.text.len() - 2);
+        // skip the quotes, columns are relative to the opening one
+        let inner = &arg.text[1..arg.text.len() - 1];
+        let mut chars = inner.chars().enumerate().map(|(i, c)| (i + 1, c));
+        while let Some((i, c)) = chars.next() {
+            char_vec.push(if c == '\\' {
+                let (_, code) = chars.next().unwrap();
+                self.escape_code(code, self.span(arg.col + i, 2))?
+            } else { c })
         }
         char_vec.push('\0');
 
         Ok(char_vec)
     }
 
-    fn parse_metainstruction(&mut self, mnemonic: &str, arg: Option<String>, instruction_addr: i64) -> ParseResult<Vec<Instruction>> {
+    // appends `bytes` to the data section and labels them with `name`
+    fn define_data(&mut self, name: &Token, bytes: &[u8], instructions: &mut [Instruction]) -> ParseResult<()> {
+        if name.kind != TokenKind::Word {
+            return Err(self.token_error(name, format!("expect `{}` to be a label name", name.text)))
+        }
+
+        let span = self.token_span(name);
+        self.check_label_name(&name.text, &span)?;
+        let label = self.qualify(&name.text, &span)?;
+        let data_addr = self.data.len() as i64;
+        self.data.extend_from_slice(bytes);
+        self.data_labels.insert(label.clone());
+        self.define_label(&label, span, data_addr, instructions)
+    }
+
+    fn parse_metainstruction(&mut self, directive: &Token, operands: &[Token], instructions: &mut Vec<Instruction>) -> ParseResult<Vec<Instruction>> {
         use Instruction as I;
 
-        match mnemonic {
-            "PushStr" if arg.is_some() => Ok(
-                self.parse_str_lit(arg.unwrap())?
+        if self.macros.contains_key(&directive.text[1..]) {
+            self.expand_macro(directive, operands, instructions)?;
+            return Ok(vec![])
+        }
+
+        let usage = |usage: &str| self.token_error(directive, format!("expected `{}`", usage));
+        let instruction_addr = instructions.len() as i64;
+        match (&directive.text[1..], operands) {
+            ("PushStr", [str_lit]) => Ok(
+                self.parse_str_lit(str_lit)?
                     .into_iter()
                     .rev()
                     .map(|c| I::Push(c as i64))
                     .collect()
                 ),
-            "Break" if arg.is_none() => {
+            ("PushStr", _) => Err(usage("@PushStr \"<string>\"")),
+            ("String", [name, str_lit]) => {
+                let bytes: String = self.parse_str_lit(str_lit)?.into_iter().collect();
+                self.define_data(name, bytes.as_bytes(), instructions)?;
+                Ok(vec![])
+            }
+            ("String", _) => Err(usage("@String <name> \"<string>\"")),
+            ("Data", [name, values @ ..]) if !values.is_empty() => {
+                let data_addr = self.data.len();
+                let mut bytes = vec![];
+                for (i, value) in self.split_operands(values)?.iter().enumerate() {
+                    let expr = self.parse_expr(value, directive)?;
+                    let target = Location::Data(data_addr + i * std::mem::size_of::<Value>());
+                    bytes.extend(self.eval_or_defer(expr, target)?.to_le_bytes());
+                }
+                self.define_data(name, &bytes, instructions)?;
+                Ok(vec![])
+            }
+            ("Data", _) => Err(usage("@Data <name> <value>, ...")),
+            ("Syscall", [name]) if name.kind == TokenKind::Word => {
+                let id = if name.text.starts_with(|c: char| c.is_ascii_digit()) {
+                    self.parse_int_lit(name, false)?
+                }
+                else {
+                    syscall_id(&name.text)
+                };
+                Ok(vec![I::Push(id), I::Syscall])
+            }
+            ("Syscall", _) => Err(usage("@Syscall <name or id>")),
+            ("Define", [name, expr @ ..]) if name.kind == TokenKind::Word && !expr.is_empty() => {
+                self.define_constant(name, expr, instructions)?;
+                Ok(vec![])
+            }
+            ("Define", _) => Err(usage("@Define <name> <expression>")),
+            ("Break", []) => {
                 self.debug_info.add_breakpoint(instruction_addr);
+                self.breakpoints.push(self.token_span(directive));
                 Ok(vec![])
             }
-            _ => Err(self.parse_error(format!("no such metainstruction `{}`", mnemonic)))
+            ("Break", _) => Err(usage("@Break")),
+            ("Macro", [name, params @ ..]) if name.kind == TokenKind::Word => {
+                self.begin_macro(name, params)?;
+                Ok(vec![])
+            }
+            ("Macro", _) => Err(usage("@Macro <name> <param>, ...")),
+            ("Include", [path]) => {
+                self.include(path, instructions)?;
+                Ok(vec![])
+            }
+            ("Include", _) => Err(usage("@Include \"<path>\"")),
+            ("If", []) => self.open_block(BlockKind::If, directive, instructions),
+            ("If", _) => Err(usage("@If")),
+            ("Else", []) => {
+                let block = self.innermost_block(directive, BlockKind::If)?;
+                if self.blocks[block].has_else {
+                    return Err(self.token_error(directive, format!("second `@Else` in the `@If` opened on line {}", self.blocks[block].span.lineno)))
+                }
+                self.blocks[block].has_else = true;
+                let expanded = vec![self.push_label(self.blocks[block].label("end"), directive, instruction_addr)?, I::Jmp];
+                self.define_hidden_label(self.blocks[block].label("else"), instruction_addr + 2, instructions);
+                Ok(expanded)
+            }
+            ("Else", _) => Err(usage("@Else")),
+            ("EndIf", []) => {
+                let block = self.innermost_block(directive, BlockKind::If)?;
+                let block = self.blocks.remove(block);
+                if !block.has_else {
+                    self.define_hidden_label(block.label("else"), instruction_addr, instructions);
+                }
+                self.define_hidden_label(block.label("end"), instruction_addr, instructions);
+                Ok(vec![])
+            }
+            ("EndIf", _) => Err(usage("@EndIf")),
+            ("While", []) => self.open_block(BlockKind::While, directive, instructions),
+            ("While", _) => Err(usage("@While")),
+            ("Loop", []) => self.open_block(BlockKind::Loop, directive, instructions),
+            ("Loop", _) => Err(usage("@Loop")),
+            ("EndWhile" | "EndLoop", []) => {
+                let kind = if directive.text == "@EndWhile" { BlockKind::While } else { BlockKind::Loop };
+                let block = self.innermost_block(directive, kind)?;
+                let block = self.blocks.remove(block);
+                let expanded = vec![self.push_label(block.label("start"), directive, instruction_addr)?, I::Jmp];
+                self.define_hidden_label(block.label("end"), instruction_addr + 2, instructions);
+                Ok(expanded)
+            }
+            ("EndWhile", _) => Err(usage("@EndWhile")),
+            ("EndLoop", _) => Err(usage("@EndLoop")),
+            ("BreakLoop" | "ContinueLoop", []) => {
+                let block = self.blocks.iter()
+                    .rev()
+                    .find(|block| block.kind != BlockKind::If)
+                    .ok_or_else(|| self.token_error(directive, format!("`{}` outside of `@While` or `@Loop`", directive.text)))?;
+                let label = block.label(if directive.text == "@BreakLoop" { "end" } else { "start" });
+                Ok(vec![self.push_label(label, directive, instruction_addr)?, I::Jmp])
+            }
+            ("BreakLoop", _) => Err(usage("@BreakLoop")),
+            ("ContinueLoop", _) => Err(usage("@ContinueLoop")),
+            ("EndMacro", _) => Err(self.token_error(directive, "`@EndMacro` without `@Macro`".to_string())),
+            (meta, _) => Err(self.token_error(directive, format!("no such metainstruction `{}`", meta)))
         }
     }
 
-    fn parse_line(&mut self, line: &str, instructions: &mut Vec<Instruction>) -> ParseResult<Option<Vec<Instruction>>> {
-        let line = line.trim();
-        if line.starts_with(';') || line.is_empty() {
-            return Ok(None)
+    fn open_block(&mut self, kind: BlockKind, directive: &Token, instructions: &mut [Instruction]) -> ParseResult<Vec<Instruction>> {
+        let instruction_addr = instructions.len() as i64;
+        self.num_blocks += 1;
+        let block = Block { kind, id: self.num_blocks, span: self.token_span(directive), has_else: false, seq: self.seq };
+
+        // `@If` and `@While` take their condition from the top of the stack
+        let expanded = match kind {
+            BlockKind::If => vec![self.push_label(block.label("else"), directive, instruction_addr)?, Instruction::Jz],
+            BlockKind::While => {
+                self.define_hidden_label(block.label("start"), instruction_addr, instructions);
+                vec![self.push_label(block.label("end"), directive, instruction_addr)?, Instruction::Jz]
+            }
+            BlockKind::Loop => {
+                self.define_hidden_label(block.label("start"), instruction_addr, instructions);
+                vec![]
+            }
+        };
+        self.blocks.push(block);
+        Ok(expanded)
+    }
+
+    // index of the innermost open block, which `directive` expects to be of `kind`
+    fn innermost_block(&self, directive: &Token, kind: BlockKind) -> ParseResult<usize> {
+        match self.blocks.last() {
+            None => Err(self.token_error(directive, format!("`{}` without a matching `{}`", directive.text, kind.directive()))),
+            Some(block) if block.kind != kind => {
+                let err = self.token_error(directive, format!(
+                    "`{}` does not match the `{}` opened on line {}",
+                    directive.text, block.kind.directive(), block.span.lineno
+                ));
+                Err(Self::with_note(err, format!("`{}` opened here", block.kind.directive()), block.span.clone()))
+            }
+            Some(_) => Ok(self.blocks.len() - 1)
         }
+    }
 
-        let mut attribs = line
-            .split_whitespace()
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty());
-        let mnemonic = attribs.next().unwrap();
+    // `PUSH` of a hidden block label
+    fn push_label(&mut self, label: String, directive: &Token, instruction_addr: i64) -> ParseResult<Instruction> {
+        let expr = Expr::Name(label, self.token_span(directive));
+        Ok(Instruction::Push(self.eval_or_defer(expr, Location::Instruction(instruction_addr as usize))?))
+    }
 
-        let mut arg = attribs.next().map(String::from);
-        if let Some(comment) = &arg && comment.starts_with(';') {
-            arg = None;
+    fn define_hidden_label(&mut self, label: String, addr: i64, instructions: &mut [Instruction]) {
+        if self.debug_info.label_at(addr).is_none() {
+            self.debug_info.add_label(addr, label.clone());
+        }
+        self.labels.insert(label.clone(), addr);
+        self.resolve_relocs(&label, instructions);
+    }
+
+    // splits comma-separated operands into the tokens of each operand
+    fn split_operands(&self, operands: &[Token]) -> ParseResult<Vec<Vec<Token>>> {
+        if operands.is_empty() {
+            return Ok(vec![])
+        }
+
+        // a comma at either end or next to another comma leaves an operand empty
+        let is_comma = |token: Option<&Token>| token.is_none_or(|token| token.kind == TokenKind::Comma);
+        let stray_comma = operands.iter()
+            .enumerate()
+            .find(|(i, token)| token.kind == TokenKind::Comma && (is_comma(i.checked_sub(1).and_then(|i| operands.get(i))) || is_comma(operands.get(i + 1))));
+        if let Some((_, comma)) = stray_comma {
+            return Err(self.token_error(comma, "expected an operand before and after `,`".to_string()))
+        }
+
+        Ok(operands.split(|token| token.kind == TokenKind::Comma)
+            .map(<[Token]>::to_vec)
+            .collect())
+    }
+
+    fn begin_macro(&mut self, name: &Token, params: &[Token]) -> ParseResult<()> {
+        let span = self.token_span(name);
+        if METAINSTRUCTIONS.contains(&name.text.as_str()) {
+            return Err(self.error_at(span, format!("macro `{}` shadows the built-in metainstruction `@{}`", name.text, name.text)))
+        }
+        if let Some(previous) = self.macros.get(&name.text) {
+            let err = self.error_at(span, format!("macro `{}` is defined multiple times", name.text));
+            return Err(Self::with_note(err, "previously defined here".to_string(), previous.span.clone()))
         }
-        else if let Some(str_lit) = &mut arg && str_lit.starts_with('"') && !str_lit.ends_with('"') {
-            for next in attribs {
-                str_lit.push(' ');
-                str_lit.push_str(next);
-                if next.ends_with('"') {
-                    break;
+
+        let mut param_names: Vec<String> = vec![];
+        for param in self.split_operands(params)? {
+            match param.as_slice() {
+                [param] if param.kind == TokenKind::Word && !param_names.contains(&param.text) => param_names.push(param.text.clone()),
+                [param] if param.kind == TokenKind::Word => return Err(self.token_error(param, format!("duplicate macro parameter `{}`", param.text))),
+                [param, ..] => return Err(self.token_error(param, "expected a parameter name".to_string())),
+                [] => unreachable!()
+            }
+        }
+
+        self.macro_def = Some((name.text.clone(), Macro {
+            params: param_names,
+            body: vec![],
+            labels: HashSet::new(),
+            file: self.filepath.clone(),
+            span,
+            seq: self.seq
+        }));
+        Ok(())
+    }
+
+    // records a line of the macro currently being defined
+    fn record_macro_line(&mut self, tokens: Vec<Token>) -> ParseResult<()> {
+        let (name, mut def) = self.macro_def.take().unwrap();
+
+        match tokens.first().map(|token| token.text.as_str()) {
+            Some("@EndMacro") => {
+                self.macros.insert(name, Rc::new(def));
+                if let Some(extra) = tokens.get(1) {
+                    return Err(self.token_error(extra, format!("unexpected `{}` after `@EndMacro`", extra.text)))
+                }
+            }
+            Some("@Macro") => {
+                let err = self.token_error(&tokens[0], format!("macro definitions cannot be nested in macro `{}`", name));
+                let err = Self::with_note(err, "enclosing macro defined here".to_string(), def.span.clone());
+                self.macro_def = Some((name, def));
+                return Err(err)
+            }
+            first => {
+                if let Some(label) = first.and_then(|first| first.strip_suffix(':')) {
+                    def.labels.insert(label.to_string());
                 }
+                def.body.push(MacroLine {
+                    lineno: self.lineno,
+                    line: self.line.clone(),
+                    tokens
+                });
+                self.macro_def = Some((name, def));
+            }
+        }
+
+        Ok(())
+    }
+
+    // replaces parameters with arguments and makes labels unique to this expansion
+    fn substitute(def: &Macro, tokens: &[Token], args: &[Vec<Token>], expansion: usize) -> Vec<Token> {
+        let mut substituted = vec![];
+        for (pos, token) in tokens.iter().enumerate() {
+            if token.kind != TokenKind::Word {
+                substituted.push(token.clone());
+            }
+            else if let Some(i) = def.params.iter().position(|param| *param == token.text) {
+                // parenthesize arguments used as operands, so that `x * 2` with `1 + 1` is `(1 + 1) * 2`
+                let group = pos > 0 && args[i].len() > 1;
+                let paren = |text: &str| Token { kind: TokenKind::Op, text: text.to_string(), col: token.col, len: token.len };
+                if group {
+                    substituted.push(paren("("));
+                }
+                substituted.extend(args[i].iter().map(|arg| Token { col: token.col, len: token.len, ..arg.clone() }));
+                if group {
+                    substituted.push(paren(")"));
+                }
+            }
+            else {
+                let (name, suffix) = token.text.strip_suffix(':')
+                    .map_or((token.text.as_str(), ""), |label| (label, ":"));
+                let text = if def.labels.contains(name) {
+                    format!("{}#{}{}", name, expansion, suffix)
+                } else {
+                    token.text.clone()
+                };
+                substituted.push(Token { text, ..token.clone() });
+            }
+        }
+        substituted
+    }
+
+    fn expand_macro(&mut self, call: &Token, operands: &[Token], instructions: &mut Vec<Instruction>) -> ParseResult<()> {
+        let name = &call.text[1..];
+        let def = Rc::clone(&self.macros[name]);
+        let call_site = self.token_span(call);
+
+        if self.expansions.iter().any(|(expanding, _)| matches!(expanding, Expansion::Macro(expanding) if expanding == name)) {
+            let err = self.error_at(call_site, format!("recursive expansion of macro `{}`", name));
+            return Err(Self::with_note(err, "macro defined here".to_string(), def.span.clone()))
+        }
+
+        let args = self.split_operands(operands)?;
+        if args.len() != def.params.len() {
+            let err = self.error_at(call_site, format!(
+                "macro `{}` expects {} argument{}, found {}",
+                name, def.params.len(), if def.params.len() == 1 { "" } else { "s" }, args.len()
+            ));
+            return Err(Self::with_note(err, "macro defined here".to_string(), def.span.clone()))
+        }
+
+        self.num_expansions += 1;
+        let expansion = self.num_expansions;
+        let call_site = self.enter(Expansion::Macro(name.to_string()), call_site, def.file.clone());
+        let scope = self.scope.clone();
+
+        for line in &def.body {
+            self.seq += 1;
+            self.lineno = line.lineno;
+            self.line = line.line.clone();
+            let tokens = Self::substitute(&def, &line.tokens, &args, expansion);
+            if let Err(err) = self.parse_tokens(&tokens, instructions) {
+                self.report(err);
+            }
+        }
+
+        // local labels following the call still belong to the caller's scope
+        self.scope = scope;
+        self.leave(call_site);
+        Ok(())
+    }
+
+    // looks up an included file next to the current file, then in the include directories
+    fn resolve_include(&self, requested: &str) -> Option<PathBuf> {
+        let current_dir = Path::new(&self.filepath).parent().unwrap_or(Path::new(""));
+        std::iter::once(current_dir)
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(requested))
+            .find(|path| path.is_file())
+    }
+
+    fn include(&mut self, path: &Token, instructions: &mut Vec<Instruction>) -> ParseResult<()> {
+        let mut requested: String = self.parse_str_lit(path)?.into_iter().collect();
+        requested.pop();
+
+        let resolved = self.resolve_include(&requested)
+            .ok_or_else(|| self.token_error(path, format!("could not find included file `{}`", requested)))?;
+        let canonical = resolved.canonicalize()
+            .map_err(|err| self.token_error(path, format!("could not open `{}`: {}", resolved.display(), err)))?;
+        if self.include_stack.contains(&canonical) {
+            return Err(self.token_error(path, format!("`{}` is included recursively", resolved.display())))
+        }
+        let file = File::open(&resolved)
+            .map_err(|err| self.token_error(path, format!("could not open `{}`: {}", resolved.display(), err)))?;
+
+        let span = self.token_span(path);
+        let include_site = self.enter(Expansion::Include, span, resolved.display().to_string());
+        self.include_stack.push(canonical);
+        let scope = self.scope.clone();
+        self.parse_lines(BufReader::new(file), instructions);
+        self.scope = scope;
+        self.include_stack.pop();
+        self.leave(include_site);
+        Ok(())
+    }
+
+    // records the lints allowed by a `; allow(<lint>, ...)` comment for this line,
+    // or for the next one if the comment is on its own line
+    fn parse_allow(&mut self, tokens: &[Token]) -> ParseResult<()> {
+        let line: Vec<char> = self.line.chars().collect();
+        let code_end = tokens.last().map_or(0, |token| token.col - 1 + token.len);
+        let Some(comment) = line[code_end..].iter().position(|c| *c == ';').map(|i| code_end + i + 1) else {
+            return Ok(())
+        };
+        let Some(start) = line[comment..].iter().position(|c| !c.is_whitespace()).map(|i| comment + i) else {
+            return Ok(())
+        };
+        if !line[start..].starts_with(&['a', 'l', 'l', 'o', 'w', '(']) {
+            return Ok(())
+        }
+
+        let list_start = start + "allow(".len();
+        let list_end = line[list_start..].iter().position(|c| *c == ')').map(|i| list_start + i)
+            .ok_or_else(|| self.error_at(self.span(start + 1, line.len() - start), "expected `)` after the allowed lints".to_string()))?;
+
+        let mut lints = HashSet::new();
+        let mut name_start = list_start;
+        for name in line[list_start..list_end].split(|c| *c == ',') {
+            let name_col = name_start + name.iter().take_while(|c| c.is_whitespace()).count();
+            let name: String = name.iter().collect();
+            lints.insert(name.trim().parse::<Lint>()
+                .map_err(|err| self.error_at(self.span(name_col + 1, name.trim().chars().count()), err))?);
+            name_start += name.chars().count() + 1;
+        }
+
+        if tokens.is_empty() {
+            self.allowed.entry((self.filepath.clone(), self.lineno + 1)).or_default().extend(lints.iter().copied());
+        }
+        self.allowed.entry((self.filepath.clone(), self.lineno)).or_default().extend(lints);
+        Ok(())
+    }
+
+    fn parse_line(&mut self, instructions: &mut Vec<Instruction>) -> ParseResult<()> {
+        let tokens = self.tokenize()?;
+        self.parse_allow(&tokens)?;
+        if self.macro_def.is_some() {
+            return self.record_macro_line(tokens)
+        }
+
+        self.parse_tokens(&tokens, instructions)
+    }
+
+    fn parse_tokens(&mut self, tokens: &[Token], instructions: &mut Vec<Instruction>) -> ParseResult<()> {
+        let Some((mnemonic, operands)) = tokens.split_first() else {
+            return Ok(())
+        };
+
+        if mnemonic.kind != TokenKind::Word {
+            return Err(self.token_error(mnemonic, format!("expected a mnemonic, label or metainstruction, found `{}`", mnemonic.text)))
+        }
+
+        let instruction_addr = instructions.len() as i64;
+        let result = self.parse_statement(mnemonic, operands, instructions);
+
+        // remember where new instructions came from, unless an expansion already did
+        for addr in instruction_addr..instructions.len() as i64 {
+            if self.debug_info.location_at(addr).is_none() {
+                self.debug_info.add_location(addr, &self.filepath, self.lineno);
             }
         }
-        else if let Some(next) = attribs.next() && !next.starts_with(';') {
-            return Err(self.parse_error(format!("too many arguments: `{}`", next)))
+        while self.instruction_spans.len() < instructions.len() {
+            self.instruction_spans.push(self.token_span(mnemonic));
         }
 
+        result
+    }
+
+    fn parse_statement(&mut self, mnemonic: &Token, operands: &[Token], instructions: &mut Vec<Instruction>) -> ParseResult<()> {
         let instruction_addr = instructions.len() as i64;
 
         // label detected
-        if let Some(label) = mnemonic.strip_suffix(':') {
-            if let Some(rel) = self.relocs.remove(&label.to_string()) { 
-                rel.iter().for_each(|ri| 
-                    if let Some(i) = instructions.get_mut(*ri as usize) {
-                        i.set_arg(instruction_addr)
-                    }
-                )
+        if let Some(label) = mnemonic.text.strip_suffix(':') {
+            if let Some(next) = operands.first() {
+                return Err(self.token_error(next, format!("unexpected `{}` after label", next.text)))
+            }
+
+            let span = self.span(mnemonic.col, mnemonic.len - 1);
+            if label.is_empty() {
+                return Err(self.token_error(mnemonic, "expected a label name before `:`".to_string()))
             }
+            self.check_label_name(label, &span)?;
+            let label = self.qualify(label, &span)?;
+            self.define_label(&label, span, instruction_addr, instructions)?;
+            self.debug_info.add_label(instruction_addr, label.clone());
 
-            self.labels.insert(label.to_string(), instruction_addr);
-            self.debug_info.add_label(instruction_addr, label.to_string());
-            Ok(None)
+            // labels generated by macro expansions don't open a new scope
+            if is_global_label(&label) {
+                self.scope = Some(label);
+            }
         }
-        else if let Some(meta) = mnemonic.strip_prefix('@'){
-            self.parse_metainstruction(meta, arg, instruction_addr).map(Some)
+        else if mnemonic.text.starts_with('@') {
+            let expanded = self.parse_metainstruction(mnemonic, operands, instructions)?;
+            instructions.extend(expanded);
         }
         else {
-            self.parse_instruction(mnemonic, arg, instruction_addr).map(|i| Some(vec![i]))
+            let instruction = self.parse_instruction(mnemonic, operands, instruction_addr)?;
+            instructions.push(instruction);
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_machine::StackMachine;
+    use Instruction as I;
+
+    fn assemble(source: &str) -> Result<Binary, ParseErrors> {
+        AsmParser::new("test.stasm".to_string()).assemble_reader(source.as_bytes())
+    }
+
+    // empty directory for the files of a test
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("stackvm-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) {
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+    }
+
+    // message, line and notes of an error, with notes as (message, line)
+    type Reported = (String, usize, Vec<(String, usize)>);
+
+    fn errors(source: &str) -> Vec<Reported> {
+        let Err(ParseErrors(errors)) = assemble(source) else {
+            panic!("assembled invalid source")
+        };
+        errors.into_iter()
+            .map(|err| match err {
+                ParseError::Parse { err, span, notes } => (err, span.lineno, notes.into_iter().map(|(note, span)| (note, span.lineno)).collect()),
+                other => panic!("unexpected error {:?}", other)
+            })
+            .collect()
+    }
+
+    // value of the single `PUSH` the source assembles to
+    fn push(operand: &str) -> Result<Value, String> {
+        match assemble(&format!("PUSH {}", operand)) {
+            Ok(binary) => match binary.instructions() {
+                [I::Push(value)] => Ok(*value),
+                other => panic!("unexpected instructions {:?}", other)
+            }
+            Err(ParseErrors(errors)) => match &errors[..] {
+                [ParseError::Parse { err, .. }] => Err(err.clone()),
+                other => panic!("unexpected errors {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(push("1 + 2 * 3"), Ok(7));
+        assert_eq!(push("(1 + 2) * 3"), Ok(9));
+        assert_eq!(push("10 - 4 - 3"), Ok(3));
+        assert_eq!(push("100 / 10 / 5"), Ok(2));
+        assert_eq!(push("7 % 4 * 2"), Ok(6));
+        assert_eq!(push("-2 * 3"), Ok(-6));
+        assert_eq!(push("-(1 + 2)"), Ok(-3));
+        assert_eq!(push("2 - -1"), Ok(3));
+        assert!(push("(1 + 2").is_err());
+        assert!(push("1 +").is_err());
+        assert!(push("1 / 0").is_err());
+    }
+
+    #[test]
+    fn constants_in_expressions() {
+        let binary = assemble("@Define SIZE 4\n@Define TOTAL SIZE * 2 + 1\nPUSH TOTAL - SIZE").unwrap();
+        assert_eq!(binary.instructions(), [I::Push(5)]);
+    }
+
+    #[test]
+    fn integer_range() {
+        assert_eq!(push("9223372036854775807"), Ok(Value::MAX));
+        assert_eq!(push("-9223372036854775808"), Ok(Value::MIN));
+        assert!(push("9223372036854775808").is_err());
+        assert!(push("-9223372036854775809").is_err());
+        assert!(push("18446744073709551616").is_err());
+        // prefixed literals may use all 64 bits
+        assert_eq!(push("0xFFFF_FFFF_FFFF_FFFF"), Ok(-1));
+        assert_eq!(push("0x8000_0000_0000_0000"), Ok(Value::MIN));
+        assert!(push("0x1_0000_0000_0000_0000").is_err());
+    }
+
+    #[test]
+    fn prefixed_literals() {
+        assert_eq!(push("0x1F"), Ok(31));
+        assert_eq!(push("0XfF"), Ok(255));
+        assert_eq!(push("0b1010"), Ok(10));
+        assert_eq!(push("0o17"), Ok(15));
+        assert_eq!(push("-0x10"), Ok(-16));
+        assert_eq!(push("007"), Ok(7));
+        assert!(push("0b102").is_err());
+        assert!(push("0o8").is_err());
+        assert!(push("0x").is_err());
+        assert!(push("12a").is_err());
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(push("1_000_000"), Ok(1_000_000));
+        assert_eq!(push("0b1010_1010"), Ok(0b1010_1010));
+        for malformed in ["1_", "1__2", "0x_1", "0b1_", "0x__"] {
+            assert!(push(malformed).is_err(), "`{}` was accepted", malformed);
+        }
+    }
+
+    #[test]
+    fn char_literals() {
+        assert_eq!(push("'A'"), Ok(65));
+        assert_eq!(push("' '"), Ok(32));
+        assert_eq!(push("'\\n'"), Ok(10));
+        assert_eq!(push("'\\t'"), Ok(9));
+        assert_eq!(push("'\\0'"), Ok(0));
+        assert_eq!(push("'\\\\'"), Ok(92));
+        assert_eq!(push("'\\''"), Ok(39));
+        assert_eq!(push("'\\\"'"), Ok(34));
+        assert_eq!(push("'a' + 1"), Ok(98));
+        assert!(push("''").is_err());
+        assert!(push("'ab'").is_err());
+        assert!(push("'\\q'").is_err());
+    }
+
+    #[test]
+    fn errors_are_in_source_order() {
+        // unresolved names and unclosed blocks are only found at the end
+        let lines: Vec<_> = errors("PUSH missing\n@If\nPUSH\n@Macro m\nNOPE\n").into_iter().map(|(_, line, _)| line).collect();
+        assert_eq!(lines, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn errors_of_includes_and_macros_are_in_parse_order() {
+        let dir = temp_dir("error-order");
+        write_files(&dir, &[
+            ("zzz.stasm", "NOPE1\n@Include \"aaa.stasm\"\nNOPE2\n@Macro m\n PUSH 1 +\n@EndMacro\nNOPE3\n@m\nPUSH missing\n"),
+            ("aaa.stasm", "NOPE4\n"),
+        ]);
+
+        let mut parser = AsmParser::new(dir.join("zzz.stasm").display().to_string());
+        let Err(ParseErrors(errors)) = parser.assemble() else {
+            panic!("assembled invalid source")
+        };
+        let positions: Vec<_> = errors.iter()
+            .map(|err| match err {
+                ParseError::Parse { span, .. } => (Path::new(&span.file).file_name().unwrap().to_str().unwrap(), span.lineno),
+                other => panic!("unexpected error {:?}", other)
+            })
+            .collect();
+        // the macro's error is reported where it is expanded
+        assert_eq!(positions, [("zzz.stasm", 1), ("aaa.stasm", 1), ("zzz.stasm", 3), ("zzz.stasm", 7), ("zzz.stasm", 5), ("zzz.stasm", 9)]);
+    }
+
+    #[test]
+    fn macro_arguments_keep_precedence() {
+        let binary = assemble("@Macro dbl x\n PUSH x * 2\n@EndMacro\n@dbl 1 + 1\n@dbl 3").unwrap();
+        assert_eq!(binary.instructions(), [I::Push(4), I::Push(6)]);
+    }
+
+    #[test]
+    fn macro_labels_are_unique_per_expansion() {
+        let mut parser = AsmParser::new("test.stasm".to_string());
+        let binary = parser.assemble_reader("@Macro skip\n PUSH end\n JMP\nend:\n@EndMacro\n@skip\n@skip\n".as_bytes()).unwrap();
+        assert_eq!(binary.instructions(), [I::Push(2), I::Jmp, I::Push(4), I::Jmp]);
+
+        let debug_info = parser.debug_info();
+        assert_eq!(debug_info.label_at(2).map(String::as_str), Some("end#1"));
+        assert_eq!(debug_info.label_at(4).map(String::as_str), Some("end#2"));
+    }
+
+    #[test]
+    fn macro_argument_count() {
+        assert_eq!(errors("@Macro add a, b\n PUSH a + b\n@EndMacro\n@add 1"), [(
+            "macro `add` expects 2 arguments, found 1".to_string(),
+            4,
+            vec![("macro defined here".to_string(), 1)]
+        )]);
+    }
+
+    #[test]
+    fn recursive_macros() {
+        assert_eq!(errors("@Macro a\n @b\n@EndMacro\n@Macro b\n @a\n@EndMacro\n@a"), [(
+            "recursive expansion of macro `a`".to_string(),
+            5,
+            vec![
+                ("macro defined here".to_string(), 1),
+                ("in expansion of macro `b`".to_string(), 2),
+                ("in expansion of macro `a`".to_string(), 7),
+            ]
+        )]);
+    }
+
+    fn instructions(source: &str) -> Vec<Instruction> {
+        assemble(source).unwrap_or_else(|err| panic!("{}", err)).instructions().to_vec()
+    }
+
+    #[test]
+    fn if_blocks() {
+        assert_eq!(instructions("@If\n PUSH 1\n@EndIf"), [I::Push(3), I::Jz, I::Push(1)]);
+        assert_eq!(instructions("@If\n PUSH 1\n@Else\n PUSH 2\n@EndIf"), [
+            I::Push(5), I::Jz, I::Push(1), I::Push(6), I::Jmp, I::Push(2),
+        ]);
+    }
+
+    #[test]
+    fn loop_blocks() {
+        assert_eq!(instructions("@While\n PUSH 1\n@EndWhile"), [I::Push(5), I::Jz, I::Push(1), I::Push(0), I::Jmp]);
+        // `@BreakLoop` and `@ContinueLoop` skip the `@If` to jump to the end and start of the loop
+        assert_eq!(instructions("@Loop\n @If\n  @BreakLoop\n @EndIf\n @ContinueLoop\n@EndLoop"), [
+            I::Push(4), I::Jz, I::Push(8), I::Jmp, I::Push(0), I::Jmp, I::Push(0), I::Jmp,
+        ]);
+    }
+
+    #[test]
+    fn nested_blocks_run() {
+        // prints the even numbers counting down from 5
+        let source = "PUSH 5\nDUP\n@While\n DUP\n PUSH 2\n MOD\n NOT\n @If\n  DUP\n  PRINTOUT\n @EndIf\n PUSH 1\n SUB\n DUP\n@EndWhile\nEXIT\n";
+        let binary = assemble(source).unwrap();
+
+        let mut output = vec![];
+        let mut machine = StackMachine::new(DebugInfo::default());
+        machine.set_output(&mut output);
+        assert_eq!(machine.run(binary.instructions()).unwrap(), 0);
+        drop(machine);
+        assert_eq!(output, b"4\n2\n");
+    }
+
+    #[test]
+    fn block_diagnostics() {
+        assert_eq!(errors("@If\n PUSH 1\n@EndWhile"), [
+            ("`@If` is missing `@EndIf`".to_string(), 1, vec![]),
+            ("`@EndWhile` does not match the `@If` opened on line 1".to_string(), 3, vec![("`@If` opened here".to_string(), 1)]),
+        ]);
+        assert_eq!(errors("@While\n PUSH 0\n"), [("`@While` is missing `@EndWhile`".to_string(), 1, vec![])]);
+        assert_eq!(errors("@EndIf"), [("`@EndIf` without a matching `@If`".to_string(), 1, vec![])]);
+        assert_eq!(errors("@If\n@Else\n@Else\n@EndIf"), [("second `@Else` in the `@If` opened on line 1".to_string(), 3, vec![])]);
+        assert_eq!(errors("@BreakLoop"), [("`@BreakLoop` outside of `@While` or `@Loop`".to_string(), 1, vec![])]);
+    }
+
+    fn file_parser(dir: &Path, name: &str) -> AsmParser {
+        AsmParser::new(dir.join(name).display().to_string())
+    }
+
+    #[test]
+    fn include_search_path() {
+        let dir = temp_dir("include-search");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("inc")).unwrap();
+        write_files(&dir, &[
+            ("src/main.stasm", "@Include \"lib.stasm\"\n@Include \"local.stasm\"\n"),
+            ("src/local.stasm", "PUSH 1\n"),
+            ("inc/lib.stasm", "PUSH 2\n"),
+            ("inc/local.stasm", "PUSH 3\n"),
+        ]);
+
+        let Err(ParseErrors(errors)) = file_parser(&dir, "src/main.stasm").assemble() else {
+            panic!("found `lib.stasm` without include directory")
+        };
+        assert!(matches!(&errors[..], [ParseError::Parse { err, .. }] if err == "could not find included file `lib.stasm`"));
+
+        // files next to the including file come first
+        let mut parser = file_parser(&dir, "src/main.stasm");
+        parser.add_include_dir(dir.join("inc").display().to_string());
+        assert_eq!(parser.assemble().unwrap().instructions(), [I::Push(2), I::Push(1)]);
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = temp_dir("include-cycle");
+        write_files(&dir, &[
+            ("c1.stasm", "@Include \"c2.stasm\"\n"),
+            ("c2.stasm", "PUSH 1\n@Include \"c1.stasm\"\n"),
+        ]);
+
+        let Err(ParseErrors(errors)) = file_parser(&dir, "c1.stasm").assemble() else {
+            panic!("assembled an include cycle")
+        };
+        match &errors[..] {
+            [ParseError::Parse { err, span, notes }] => {
+                assert!(err.ends_with("c1.stasm` is included recursively"), "{}", err);
+                assert!(span.file.ends_with("c2.stasm") && span.lineno == 2);
+                assert!(matches!(&notes[..], [(note, site)] if note == "included from here" && site.file.ends_with("c1.stasm")));
+            }
+            other => panic!("unexpected errors {:?}", other)
+        }
+    }
+
+    #[test]
+    fn include_locations() {
+        let dir = temp_dir("include-locations");
+        write_files(&dir, &[
+            ("main.stasm", "PUSH 1\n@Include \"lib.stasm\"\nEXIT\n"),
+            ("lib.stasm", "PUSH 2\n\nPUSH 3\n"),
+        ]);
+
+        let mut parser = file_parser(&dir, "main.stasm");
+        parser.assemble().unwrap();
+        let debug_info = parser.debug_info();
+        let locations: Vec<_> = (0..4)
+            .map(|addr| debug_info.location_at(addr).map(|(file, lineno)| (Path::new(file).file_name().unwrap().to_str().unwrap().to_string(), lineno)))
+            .collect();
+        assert_eq!(locations, [
+            Some(("main.stasm".to_string(), 1)),
+            Some(("lib.stasm".to_string(), 1)),
+            Some(("lib.stasm".to_string(), 3)),
+            Some(("main.stasm".to_string(), 3)),
+        ]);
     }
 }
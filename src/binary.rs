@@ -1,6 +1,6 @@
-use std::{fs::File, io::{BufWriter, Write, Read}};
+use std::{collections::HashMap, fs::File, io::{BufWriter, Write, Read}};
 
-use crate::instruction::{Instruction, Value};
+use crate::{debug_info::DebugInfo, instruction::{Instruction, Value, SHORT_PUSH}};
 
 use colored::Colorize;
 
@@ -9,7 +9,23 @@ pub type LoadResult<T> = Result<T, LoadError>;
 
 #[derive(Debug)]
 pub enum LoadError {
-    Load(String),
+    /// the file doesn't start with the `.SPVM` magic
+    WrongFormat,
+    /// the file was written in a format version this build can't read
+    UnsupportedVersion(u16),
+    /// the header sets flags this build doesn't know
+    UnsupportedFlags(u16),
+    /// the checksum stored in the header doesn't match the file's contents
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32
+    },
+    /// the file is truncated or its sections are malformed
+    Corrupted(String),
+    /// an object file was given where a binary was expected
+    ObjectFile,
+    /// a binary was given where an object file was expected
+    NotAnObject,
     Io(std::io::Error)
 }
 
@@ -21,113 +37,366 @@ impl From<std::io::Error> for LoadError {
 
 impl std::fmt::Display for LoadError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = "Load Error:".bold().red();
         match self {
-            Self::Load(err) => write!(f, "{} {}", "Load Error:".bold().red(), err),
+            Self::WrongFormat => write!(f, "{} wrong file format", prefix),
+            Self::UnsupportedVersion(version) => write!(f, "{} unsupported format version {}, expected {}", prefix, version, FORMAT_VERSION),
+            Self::UnsupportedFlags(flags) => write!(f, "{} unsupported header flags {:#06x}", prefix, flags),
+            Self::ChecksumMismatch { expected, actual } => write!(f, "{} checksum mismatch (expected {:08x}, found {:08x}), the file is corrupted", prefix, expected, actual),
+            Self::Corrupted(err) => write!(f, "{} corrupted file: {}", prefix, err),
+            Self::ObjectFile => write!(f, "{} this is an object file, link it with `stackvm link` first", prefix),
+            Self::NotAnObject => write!(f, "{} expected an object file, found a linked binary", prefix),
             Self::Io(err) => write!(f, "{} {}", "Io:".bold().red(), err)
         }  
     }
 }
 
-#[derive(Default)]
-#[repr(C)]
-struct Header {
-    num_instructions: usize,
+impl std::error::Error for LoadError {}
+
+const MAGIC: [u8; 5] = [
+    b'.',
+    b'S',
+    b'P',
+    b'V',
+    b'M'
+];
+
+/// Version of the binary format written by `Binary::save_to`.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// set for object files, which need to be linked before they can run
+pub(crate) const FLAG_OBJECT: u16 = 1;
+/// set if the code section uses `Encoding::Compact`
+const FLAG_COMPACT: u16 = 2;
+
+// header flags understood by this version
+const KNOWN_FLAGS: u16 = FLAG_OBJECT | FLAG_COMPACT;
+
+/// How instructions are stored in the code section.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// 2-byte opcodes, `PUSH` followed by its 8-byte argument, see `Instruction::as_bytes`
+    #[default]
+    Fixed,
+    /// 1-byte opcodes and variable-length arguments, see `Instruction::as_compact_bytes`
+    Compact,
 }
 
-impl Header {
-    fn as_bytes(&self) -> &[u8] {
-        unsafe {
-            core::slice::from_raw_parts(self as *const Self as *const u8, core::mem::size_of::<Header>())
+impl Encoding {
+    pub(crate) fn flags(&self) -> u16 {
+        match self {
+            Self::Fixed => 0,
+            Self::Compact => FLAG_COMPACT,
         }
     }
 
-    fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            core::mem::transmute((self, core::mem::size_of::<Header>()))
+    pub(crate) fn from_flags(flags: u16) -> Self {
+        if flags & FLAG_COMPACT != 0 { Self::Compact } else { Self::Fixed }
+    }
+
+    pub fn encode(&self, instruction: &Instruction) -> Vec<u8> {
+        match self {
+            Self::Fixed => instruction.as_bytes(),
+            Self::Compact => instruction.as_compact_bytes(),
         }
     }
 }
 
-#[derive(Default)]
-pub struct Binary {
-    header: Header,
-    instructions: Vec<Instruction>
+// All integers are little-endian. The header is followed by the section
+// table and the section contents:
+//
+//   magic           [u8; 5]
+//   version         u16
+//   flags           u16
+//   num_sections    u16
+//   checksum        u32, CRC-32 of everything after it
+//   sections        [(kind: u16, offset: u64, size: u64); num_sections]
+//
+// The code and data sections are required, the debug section is optional.
+// Object files set `FLAG_OBJECT` and also have symbol and relocation sections.
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 2 + 2 + 4;
+const SECTION_ENTRY_SIZE: usize = 2 + 8 + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum SectionKind {
+    Code = 1,
+    Data = 2,
+    /// optional, see `DebugInfo::encode`
+    Debug = 3,
+    /// labels defined by an object file
+    Symbols = 4,
+    /// expressions an object file needs the linker to evaluate
+    Relocations = 5,
 }
 
-const MAGIC: [u8; 5] = [
-    b'.',
-    b'S',
-    b'P',
-    b'V',
-    b'M'
-];
+impl SectionKind {
+    fn from_id(id: u16) -> LoadResult<Self> {
+        match id {
+            1 => Ok(Self::Code),
+            2 => Ok(Self::Data),
+            3 => Ok(Self::Debug),
+            4 => Ok(Self::Symbols),
+            5 => Ok(Self::Relocations),
+            _ => Err(LoadError::Corrupted(format!("unknown section kind {}", id)))
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Binary {
+    instructions: Vec<Instruction>,
+    data: Vec<u8>,
+    debug_info: Option<DebugInfo>,
+    encoding: Encoding
+}
 
 impl Binary {
-    pub fn from_instructions(instructions: Vec<Instruction>) -> Binary {
+    pub fn new(instructions: Vec<Instruction>, data: Vec<u8>) -> Binary {
         Binary { 
-            header: Header {
-                num_instructions: instructions.len()
-            },
-            instructions
+            instructions,
+            data,
+            debug_info: None,
+            encoding: Encoding::Fixed
         } 
     }
 
-    pub fn instructions(self) -> Vec<Instruction> {
-        self.instructions
+    /// Encoding of the code section, the one the binary was loaded with or `Encoding::Fixed`.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Sets the encoding `save_to` writes the code section with.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Labels, breakpoints and source locations saved along with the program.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Sets the debug info saved with the binary, `None` strips it.
+    pub fn set_debug_info(&mut self, debug_info: Option<DebugInfo>) {
+        self.debug_info = debug_info;
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Contents of the data section, loaded to address 0 of the VM memory.
+    pub fn data(&self) -> &[u8] {
+        &self.data
     }
 
     pub fn load_from(filepath: String) -> LoadResult<Binary> {
-        let mut binary = Binary::default();
-        let mut file = File::open(filepath)?;
+        Self::from_bytes(&std::fs::read(filepath)?)
+    }
 
-        let mut magic = [0; MAGIC.len()];
-        file.read_exact(&mut magic)?;
+    pub fn save_to(self, filepath: String) -> SaveResult<()> {
+        self.write_to(BufWriter::new(File::create(filepath)?))
+    }
 
-        if magic != MAGIC {
-            return Err(LoadError::Load("wrong file format".to_string()))
-        }
+    /// Reads a binary until the end of `reader`.
+    pub fn read_from(mut reader: impl Read) -> LoadResult<Binary> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
 
-        file.read_exact(binary.header.as_bytes_mut())?;
-        binary.instructions.reserve_exact(binary.header.num_instructions);
+    pub fn write_to(&self, mut writer: impl Write) -> SaveResult<()> {
+        writer.write_all(&self.to_bytes())?;
+        writer.flush()
+    }
 
-        while binary.instructions.len() < binary.header.num_instructions {
-            binary.instructions.push(read_instruction(&mut file)?);
+    /// Decodes a binary in the format written by `to_bytes`, e.g. one embedded with `include_bytes!`.
+    pub fn from_bytes(bytes: &[u8]) -> LoadResult<Binary> {
+        let (flags, sections) = decode_sections(bytes)?;
+        if flags & FLAG_OBJECT != 0 {
+            return Err(LoadError::ObjectFile)
         }
 
+        let encoding = Encoding::from_flags(flags);
+        let mut binary = Binary::new(
+            decode_code(required_section(&sections, SectionKind::Code)?, encoding)?,
+            required_section(&sections, SectionKind::Data)?.to_vec()
+        );
+        binary.set_encoding(encoding);
+        binary.set_debug_info(sections.get(&SectionKind::Debug).map(|debug| DebugInfo::decode(debug)).transpose()?);
         Ok(binary)
     }
 
-    pub fn save_to(self, filepath: String) -> SaveResult<()> {
-        let file = File::create(filepath)?;
-        let mut writer = BufWriter::new(file);
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let code = encode_code(&self.instructions, self.encoding);
+        let debug = self.debug_info.as_ref().map(DebugInfo::encode);
+        let mut sections = vec![(SectionKind::Code, code.as_slice()), (SectionKind::Data, self.data.as_slice())];
+        if let Some(debug) = &debug {
+            sections.push((SectionKind::Debug, debug));
+        }
+        encode_sections(self.encoding.flags(), &sections)
+    }
+}
 
-        writer.write_all(&MAGIC)?;
-        writer.write_all(self.header.as_bytes())?;
+pub(crate) fn encode_code(instructions: &[Instruction], encoding: Encoding) -> Vec<u8> {
+    instructions.iter()
+        .flat_map(|instruction| encoding.encode(instruction))
+        .collect()
+}
+
+pub(crate) fn decode_code(mut code: &[u8], encoding: Encoding) -> LoadResult<Vec<Instruction>> {
+    let mut instructions = vec![];
+    while !code.is_empty() {
+        instructions.push(read_instruction(&mut code, encoding)?);
+    }
+    Ok(instructions)
+}
+
+// writes the header, section table and sections
+pub(crate) fn encode_sections(flags: u16, sections: &[(SectionKind, &[u8])]) -> Vec<u8> {
+    // everything covered by the checksum
+    let mut checked = vec![];
+    let mut offset = HEADER_SIZE + sections.len() * SECTION_ENTRY_SIZE;
+    for (kind, contents) in sections {
+        checked.extend((*kind as u16).to_le_bytes());
+        checked.extend((offset as u64).to_le_bytes());
+        checked.extend((contents.len() as u64).to_le_bytes());
+        offset += contents.len();
+    }
+    for (_, contents) in sections {
+        checked.extend_from_slice(contents);
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + checked.len());
+    bytes.extend(MAGIC);
+    bytes.extend(FORMAT_VERSION.to_le_bytes());
+    bytes.extend(flags.to_le_bytes());
+    bytes.extend((sections.len() as u16).to_le_bytes());
+    bytes.extend(crc32(&checked).to_le_bytes());
+    bytes.extend(checked);
+    bytes
+}
+
+// validates the header and returns the flags and the contents of each section
+pub(crate) fn decode_sections(bytes: &[u8]) -> LoadResult<(u16, HashMap<SectionKind, &[u8]>)> {
+    if !bytes.starts_with(&MAGIC) {
+        return Err(LoadError::WrongFormat)
+    }
 
-        for instruction in self.instructions {
-            writer.write_all(&instruction.as_bytes())?;
+    let mut reader = &bytes[MAGIC.len()..];
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if version != FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version))
+    }
+    let flags = u16::from_le_bytes(read_array(&mut reader)?);
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(LoadError::UnsupportedFlags(flags & !KNOWN_FLAGS))
+    }
+    let num_sections = u16::from_le_bytes(read_array(&mut reader)?);
+    let expected = u32::from_le_bytes(read_array(&mut reader)?);
+    let actual = crc32(reader);
+    if expected != actual {
+        return Err(LoadError::ChecksumMismatch { expected, actual })
+    }
+
+    let mut sections = HashMap::new();
+    for _ in 0..num_sections {
+        let kind = SectionKind::from_id(u16::from_le_bytes(read_array(&mut reader)?))?;
+        let offset = u64::from_le_bytes(read_array(&mut reader)?);
+        let size = u64::from_le_bytes(read_array(&mut reader)?);
+        let contents = usize::try_from(offset).ok()
+            .zip(usize::try_from(size).ok())
+            .and_then(|(offset, size)| bytes.get(offset..offset.checked_add(size)?))
+            .ok_or_else(|| LoadError::Corrupted(format!("{:?} section extends past the end of the file", kind)))?;
+
+        if sections.insert(kind, contents).is_some() {
+            return Err(LoadError::Corrupted(format!("duplicate {:?} section", kind)))
         }
+    }
+
+    Ok((flags, sections))
+}
 
-        writer.flush()?;
+pub(crate) fn required_section<'a>(sections: &HashMap<SectionKind, &'a [u8]>, kind: SectionKind) -> LoadResult<&'a [u8]> {
+    sections.get(&kind)
+        .copied()
+        .ok_or_else(|| LoadError::Corrupted(format!("missing {:?} section", kind)))
+}
 
-        Ok(())
+// CRC-32 (IEEE 802.3) of `bytes`
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
     }
+    !crc
+}
+
+// strings are prefixed with their u32 length
+pub(crate) fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend((s.len() as u32).to_le_bytes());
+    bytes.extend(s.as_bytes());
 }
 
-fn read_instruction(file: &mut File) -> LoadResult<Instruction> {
-    fn read_arg(file: &mut File) -> LoadResult<Value> {
-        let mut arg_bytes = [0; std::mem::size_of::<Value>()];
-        file.read_exact(&mut arg_bytes)?;
-        Ok(Value::from_le_bytes(arg_bytes))
+pub(crate) fn read_str(bytes: &mut &[u8]) -> LoadResult<String> {
+    let len = u32::from_le_bytes(read_array(bytes)?) as usize;
+    if bytes.len() < len {
+        return Err(LoadError::Corrupted("unexpected end of file".to_string()))
     }
-    
-    let mut id_bytes = [0u8, 0];
-    file.read_exact(&mut id_bytes)?;
-    
+    let (s, rest) = bytes.split_at(len);
+    *bytes = rest;
+    String::from_utf8(s.to_vec())
+        .map_err(|_| LoadError::Corrupted("invalid UTF-8 in string".to_string()))
+}
+
+pub(crate) fn read_array<const N: usize>(reader: &mut impl Read) -> LoadResult<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes).map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => LoadError::Corrupted("unexpected end of file".to_string()),
+        _ => LoadError::Io(err)
+    })?;
+    Ok(bytes)
+}
+
+// zigzag-encoded LEB128, see `Instruction::as_compact_bytes`
+fn read_varint(reader: &mut impl Read) -> LoadResult<Value> {
+    let mut zigzag = 0u64;
+    for shift in (0..64).step_by(7) {
+        let [byte] = read_array(reader)?;
+        if shift == 63 && byte > 1 {
+            break
+        }
+        zigzag |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(unzigzag(zigzag))
+        }
+    }
+    Err(LoadError::Corrupted("integer longer than 64 bits".to_string()))
+}
+
+fn unzigzag(zigzag: u64) -> Value {
+    (zigzag >> 1) as Value ^ -((zigzag & 1) as Value)
+}
+
+fn read_instruction(reader: &mut impl Read, encoding: Encoding) -> LoadResult<Instruction> {
     use Instruction as I;
-    let mnemonic = u16::from_le_bytes(id_bytes);
+    let mnemonic = match encoding {
+        Encoding::Fixed => u16::from_le_bytes(read_array(reader)?),
+        Encoding::Compact => {
+            let [opcode] = read_array(reader)?;
+            if opcode & SHORT_PUSH != 0 {
+                return Ok(I::Push(unzigzag((opcode & !SHORT_PUSH) as u64)))
+            }
+            opcode as u16
+        }
+    };
     match mnemonic {
-        0 => Ok(I::Push(read_arg(file)?)),
+        0 => Ok(I::Push(match encoding {
+            Encoding::Fixed => Value::from_le_bytes(read_array(reader)?),
+            Encoding::Compact => read_varint(reader)?,
+        })),
         1 => Ok(I::Pop),
         2 => Ok(I::Dup),
         3 => Ok(I::Swap),
@@ -142,6 +411,167 @@ fn read_instruction(file: &mut File) -> LoadResult<Instruction> {
         12 => Ok(I::Printout),
         13 => Ok(I::Call),
         14 => Ok(I::Printstr),
-        _ => Err(LoadError::Load(format!("no such mnemonic `{}`", mnemonic)))
+        15 => Ok(I::Eq),
+        16 => Ok(I::Neq),
+        17 => Ok(I::Lt),
+        18 => Ok(I::Le),
+        19 => Ok(I::Gt),
+        20 => Ok(I::Ge),
+        21 => Ok(I::And),
+        22 => Ok(I::Or),
+        23 => Ok(I::Xor),
+        24 => Ok(I::Not),
+        25 => Ok(I::Shl),
+        26 => Ok(I::Shr),
+        27 => Ok(I::Mod),
+        28 => Ok(I::Ret),
+        29 => Ok(I::Load),
+        30 => Ok(I::Store),
+        31 => Ok(I::Load8),
+        32 => Ok(I::Store8),
+        33 => Ok(I::Printmem),
+        34 => Ok(I::Readchar),
+        35 => Ok(I::Readint),
+        36 => Ok(I::Readline),
+        37 => Ok(I::Syscall),
+        _ => Err(LoadError::Corrupted(format!("no such mnemonic `{}`", mnemonic)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instruction as I;
+
+    fn program() -> Binary {
+        let mut debug_info = DebugInfo::default();
+        debug_info.add_label(0, "main".to_string());
+        debug_info.add_breakpoint(2);
+        debug_info.add_location(1, "main.stasm", 3);
+
+        let mut binary = Binary::new(
+            vec![I::Push(1), I::Push(-300), I::Add, I::Printout, I::Push(Value::MIN), I::Exit],
+            b"data\0".to_vec()
+        );
+        binary.set_debug_info(Some(debug_info));
+        binary
+    }
+
+    fn assert_round_trip(encoding: Encoding) {
+        let mut binary = program();
+        binary.set_encoding(encoding);
+        let loaded = Binary::from_bytes(&binary.to_bytes()).unwrap();
+
+        assert_eq!(loaded.encoding(), encoding);
+        assert_eq!(loaded.instructions(), binary.instructions());
+        assert_eq!(loaded.data(), binary.data());
+        let debug_info = loaded.debug_info().unwrap();
+        assert_eq!(debug_info.label_at(0).map(String::as_str), Some("main"));
+        assert!(debug_info.breakpoint_at(2));
+        assert_eq!(debug_info.location_at(1), Some(("main.stasm", 3)));
+    }
+
+    // recomputes the checksum after the test tampered with the file
+    fn reseal(bytes: &mut [u8]) {
+        let crc = crc32(&bytes[HEADER_SIZE..]);
+        bytes[HEADER_SIZE - 4..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn round_trip_fixed() {
+        assert_round_trip(Encoding::Fixed);
+    }
+
+    #[test]
+    fn round_trip_without_debug_info() {
+        let mut binary = program();
+        binary.set_debug_info(None);
+        assert!(Binary::from_bytes(&binary.to_bytes()).unwrap().debug_info().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = program().to_bytes();
+        assert!(matches!(Binary::from_bytes(&bytes[..HEADER_SIZE - 2]), Err(LoadError::Corrupted(_))));
+        assert!(matches!(Binary::from_bytes(&bytes[..bytes.len() - 1]), Err(LoadError::ChecksumMismatch { .. })));
+
+        let mut truncated = bytes[..bytes.len() - 1].to_vec();
+        reseal(&mut truncated);
+        assert!(matches!(Binary::from_bytes(&truncated), Err(LoadError::Corrupted(_))));
+    }
+
+    #[test]
+    fn rejects_wrong_checksum() {
+        let mut bytes = program().to_bytes();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(matches!(Binary::from_bytes(&bytes), Err(LoadError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_wrong_magic() {
+        let mut bytes = program().to_bytes();
+        bytes[0] = b'!';
+        assert!(matches!(Binary::from_bytes(&bytes), Err(LoadError::WrongFormat)));
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut bytes = program().to_bytes();
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(Binary::from_bytes(&bytes), Err(LoadError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let mut bytes = program().to_bytes();
+        bytes[MAGIC.len() + 2..MAGIC.len() + 4].copy_from_slice(&0x8000u16.to_le_bytes());
+        assert!(matches!(Binary::from_bytes(&bytes), Err(LoadError::UnsupportedFlags(0x8000))));
+    }
+
+    #[test]
+    fn round_trip_compact() {
+        assert_round_trip(Encoding::Compact);
+    }
+
+    #[test]
+    fn compact_is_smaller() {
+        let mut compact = program();
+        compact.set_encoding(Encoding::Compact);
+        assert!(compact.to_bytes().len() < program().to_bytes().len());
+    }
+
+    #[test]
+    fn varint_edge_values() {
+        let cases: [(Value, &[u8]); 8] = [
+            (0, &[0x80]),
+            (-1, &[0x81]),
+            (-64, &[0xff]),
+            (63, &[0xfe]),
+            (64, &[0x00, 0x80, 0x01]),
+            (-65, &[0x00, 0x81, 0x01]),
+            (Value::MAX, &[0x00, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+            (Value::MIN, &[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+        ];
+        for (value, encoded) in cases {
+            assert_eq!(I::Push(value).as_compact_bytes(), encoded, "encoding {}", value);
+            let mut reader = encoded;
+            assert_eq!(read_instruction(&mut reader, Encoding::Compact).unwrap(), I::Push(value), "decoding {}", value);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_varints() {
+        // more than 64 bits
+        let mut reader: &[u8] = &[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02];
+        assert!(matches!(read_instruction(&mut reader, Encoding::Compact), Err(LoadError::Corrupted(_))));
+        // continuation bit set on the last byte
+        let mut reader: &[u8] = &[0x00, 0x80];
+        assert!(matches!(read_instruction(&mut reader, Encoding::Compact), Err(LoadError::Corrupted(_))));
     }
 }
@@ -1,10 +1,15 @@
 use std::collections::{HashSet, HashMap};
 
+use crate::binary::{read_array, read_str, write_str, LoadError, LoadResult};
+
 
 #[derive(Default, Clone, Debug)]
 pub struct DebugInfo {
     breakpoints: HashSet<i64>,
     labels: HashMap<i64, String>,
+    files: Vec<String>,
+    // file index and line number of each instruction
+    locations: HashMap<i64, (usize, usize)>,
     verbose: bool
 }
 
@@ -25,6 +30,30 @@ impl DebugInfo {
         self.labels.get(&addr)
     }
 
+    pub fn add_location(&mut self, addr: i64, file: &str, lineno: usize) {
+        let file_index = self.files.iter()
+            .position(|known| known == file)
+            .unwrap_or_else(|| {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            });
+        self.locations.insert(addr, (file_index, lineno));
+    }
+
+    /// Source file and line number the instruction at `addr` was assembled from.
+    pub fn location_at(&self, addr: i64) -> Option<(&str, usize)> {
+        self.locations.get(&addr).map(|(file, lineno)| (self.files[*file].as_str(), *lineno))
+    }
+
+    /// Adds the debug info of a module whose code starts at `code_base`.
+    pub(crate) fn append(&mut self, other: &DebugInfo, code_base: i64) {
+        self.breakpoints.extend(other.breakpoints.iter().map(|addr| addr + code_base));
+        self.labels.extend(other.labels.iter().map(|(addr, label)| (addr + code_base, label.clone())));
+        for (addr, (file, lineno)) in &other.locations {
+            self.add_location(addr + code_base, &other.files[*file], *lineno);
+        }
+    }
+
     pub fn verbose(&self) -> bool {
         self.verbose
     }
@@ -32,4 +61,80 @@ impl DebugInfo {
     pub fn set_verbose(&mut self, verbose: bool) {
         self.verbose = verbose;
     }
+
+    // Layout of the debug section, all integers little-endian and strings
+    // prefixed with their u32 length:
+    //
+    //   files        u32 count, [string]
+    //   labels       u32 count, [(addr: i64, string)]
+    //   breakpoints  u32 count, [addr: i64]
+    //   locations    u32 count, [(addr: i64, file: u32, lineno: u32)]
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend((self.files.len() as u32).to_le_bytes());
+        for file in &self.files {
+            write_str(&mut bytes, file);
+        }
+
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort();
+        bytes.extend((labels.len() as u32).to_le_bytes());
+        for (addr, label) in labels {
+            bytes.extend(addr.to_le_bytes());
+            write_str(&mut bytes, label);
+        }
+
+        let mut breakpoints: Vec<_> = self.breakpoints.iter().collect();
+        breakpoints.sort();
+        bytes.extend((breakpoints.len() as u32).to_le_bytes());
+        for addr in breakpoints {
+            bytes.extend(addr.to_le_bytes());
+        }
+
+        let mut locations: Vec<_> = self.locations.iter().collect();
+        locations.sort();
+        bytes.extend((locations.len() as u32).to_le_bytes());
+        for (addr, (file, lineno)) in locations {
+            bytes.extend(addr.to_le_bytes());
+            bytes.extend((*file as u32).to_le_bytes());
+            bytes.extend((*lineno as u32).to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub(crate) fn decode(mut bytes: &[u8]) -> LoadResult<Self> {
+        fn get_u32(bytes: &mut &[u8]) -> LoadResult<u32> {
+            Ok(u32::from_le_bytes(read_array(bytes)?))
+        }
+        fn get_i64(bytes: &mut &[u8]) -> LoadResult<i64> {
+            Ok(i64::from_le_bytes(read_array(bytes)?))
+        }
+
+        let mut debug_info = Self::default();
+        for _ in 0..get_u32(&mut bytes)? {
+            debug_info.files.push(read_str(&mut bytes)?);
+        }
+        for _ in 0..get_u32(&mut bytes)? {
+            let addr = get_i64(&mut bytes)?;
+            debug_info.labels.insert(addr, read_str(&mut bytes)?);
+        }
+        for _ in 0..get_u32(&mut bytes)? {
+            debug_info.breakpoints.insert(get_i64(&mut bytes)?);
+        }
+        for _ in 0..get_u32(&mut bytes)? {
+            let addr = get_i64(&mut bytes)?;
+            let file = get_u32(&mut bytes)? as usize;
+            let lineno = get_u32(&mut bytes)? as usize;
+            if file >= debug_info.files.len() {
+                return Err(LoadError::Corrupted(format!("debug location refers to unknown file {}", file)))
+            }
+            debug_info.locations.insert(addr, (file, lineno));
+        }
+
+        if !bytes.is_empty() {
+            return Err(LoadError::Corrupted("trailing bytes in the debug section".to_string()))
+        }
+        Ok(debug_info)
+    }
 }
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use crate::{assembler::Span, binary::{read_array, read_str, write_str, LoadError, LoadResult}, instruction::Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl BinOp {
+    const ALL: [Self; 5] = [Self::Add, Self::Sub, Self::Mul, Self::Div, Self::Mod];
+}
+
+// deepest nesting of operators accepted from an object file
+const MAX_DECODE_DEPTH: usize = 256;
+
+/// An assemble-time constant expression.
+#[derive(Debug, Clone)]
+pub(crate) enum Expr {
+    Value(Value),
+    /// reference to a constant or label
+    Name(String, Span),
+    Neg(Box<Expr>, Span),
+    /// the span points at the operator
+    Binary(BinOp, Box<Expr>, Box<Expr>, Span),
+}
+
+pub(crate) enum EvalError {
+    /// the name is neither a constant nor a label defined so far
+    Unresolved(String, Span),
+    Invalid(String, Span),
+}
+
+impl Expr {
+    /// Evaluates the expression, looking up names first in `constants`, then in `labels`.
+    pub(crate) fn eval(&self, constants: &HashMap<String, (Expr, Span)>, labels: &HashMap<String, Value>) -> Result<Value, EvalError> {
+        let overflow = |span: &Span| EvalError::Invalid("arithmetic overflow in constant expression".to_string(), span.clone());
+
+        match self {
+            Self::Value(value) => Ok(*value),
+            Self::Name(name, span) => match constants.get(name) {
+                Some((expr, _)) => expr.eval(constants, labels),
+                None => labels.get(name)
+                    .copied()
+                    .ok_or_else(|| EvalError::Unresolved(name.clone(), span.clone()))
+            },
+            Self::Neg(expr, span) => expr.eval(constants, labels)?
+                .checked_neg()
+                .ok_or_else(|| overflow(span)),
+            Self::Binary(op, lhs, rhs, span) => {
+                let lhs = lhs.eval(constants, labels)?;
+                let rhs = rhs.eval(constants, labels)?;
+                if matches!(op, BinOp::Div | BinOp::Mod) && rhs == 0 {
+                    return Err(EvalError::Invalid("division by zero in constant expression".to_string(), span.clone()))
+                }
+
+                match op {
+                    BinOp::Add => lhs.checked_add(rhs),
+                    BinOp::Sub => lhs.checked_sub(rhs),
+                    BinOp::Mul => lhs.checked_mul(rhs),
+                    BinOp::Div => lhs.checked_div(rhs),
+                    BinOp::Mod => lhs.checked_rem(rhs),
+                }
+                .ok_or_else(|| overflow(span))
+            }
+        }
+    }
+
+    /// Calls `f` with every name referenced by the expression.
+    pub(crate) fn visit_names<'a>(&'a self, f: &mut impl FnMut(&'a str, &'a Span)) {
+        match self {
+            Self::Value(_) => {}
+            Self::Name(name, span) => f(name, span),
+            Self::Neg(expr, _) => expr.visit_names(f),
+            Self::Binary(_, lhs, rhs, _) => {
+                lhs.visit_names(f);
+                rhs.visit_names(f);
+            }
+        }
+    }
+
+    /// Replaces references to `constants` with their definitions, leaving
+    /// only label references.
+    pub(crate) fn expand_constants(&self, constants: &HashMap<String, (Expr, Span)>) -> Expr {
+        match self {
+            Self::Value(_) => self.clone(),
+            Self::Name(name, _) => match constants.get(name) {
+                Some((expr, _)) => expr.expand_constants(constants),
+                None => self.clone()
+            },
+            Self::Neg(expr, span) => Self::Neg(Box::new(expr.expand_constants(constants)), span.clone()),
+            Self::Binary(op, lhs, rhs, span) => Self::Binary(
+                *op,
+                Box::new(lhs.expand_constants(constants)),
+                Box::new(rhs.expand_constants(constants)),
+                span.clone()
+            ),
+        }
+    }
+
+    // Serialized as a tag byte followed by the operands:
+    // 0 value (i64), 1 name (string), 2 negation, 3 binary operation (op byte, lhs, rhs)
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::Value(value) => {
+                bytes.push(0);
+                bytes.extend(value.to_le_bytes());
+            }
+            Self::Name(name, _) => {
+                bytes.push(1);
+                write_str(bytes, name);
+            }
+            Self::Neg(expr, _) => {
+                bytes.push(2);
+                expr.encode(bytes);
+            }
+            Self::Binary(op, lhs, rhs, _) => {
+                bytes.push(3);
+                bytes.push(*op as u8);
+                lhs.encode(bytes);
+                rhs.encode(bytes);
+            }
+        }
+    }
+
+    /// Decodes an expression written by `encode`. Spans are lost.
+    pub(crate) fn decode(bytes: &mut &[u8]) -> LoadResult<Self> {
+        Self::decode_nested(bytes, MAX_DECODE_DEPTH)
+    }
+
+    // `depth` limits the nesting, so that crafted files can't overflow the stack
+    fn decode_nested(bytes: &mut &[u8], depth: usize) -> LoadResult<Self> {
+        let Some(depth) = depth.checked_sub(1) else {
+            return Err(LoadError::Corrupted("expression nested too deeply".to_string()))
+        };
+
+        let [tag] = read_array(bytes)?;
+        match tag {
+            0 => Ok(Self::Value(Value::from_le_bytes(read_array(bytes)?))),
+            1 => Ok(Self::Name(read_str(bytes)?, Span::default())),
+            2 => Ok(Self::Neg(Box::new(Self::decode_nested(bytes, depth)?), Span::default())),
+            3 => {
+                let [op] = read_array(bytes)?;
+                let op = *BinOp::ALL.get(op as usize)
+                    .ok_or_else(|| LoadError::Corrupted(format!("unknown operator {} in expression", op)))?;
+                let lhs = Self::decode_nested(bytes, depth)?;
+                let rhs = Self::decode_nested(bytes, depth)?;
+                Ok(Self::Binary(op, Box::new(lhs), Box::new(rhs), Span::default()))
+            }
+            _ => Err(LoadError::Corrupted(format!("unknown expression tag {}", tag)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_round_trip() {
+        // -(a + 2) * 3
+        let span = Span::default;
+        let sum = Expr::Binary(BinOp::Add, Box::new(Expr::Name("a".to_string(), span())), Box::new(Expr::Value(2)), span());
+        let expr = Expr::Binary(BinOp::Mul, Box::new(Expr::Neg(Box::new(sum), span())), Box::new(Expr::Value(3)), span());
+
+        let mut bytes = vec![];
+        expr.encode(&mut bytes);
+        let mut reader = bytes.as_slice();
+        let decoded = Expr::decode(&mut reader).unwrap();
+        assert!(reader.is_empty());
+
+        let labels = HashMap::from([("a".to_string(), 1)]);
+        assert!(matches!(decoded.eval(&HashMap::new(), &labels), Ok(-9)));
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let mut bytes = vec![2; 1_000_000];
+        Expr::Value(1).encode(&mut bytes);
+        assert!(matches!(Expr::decode(&mut bytes.as_slice()), Err(LoadError::Corrupted(_))));
+    }
+}
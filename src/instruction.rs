@@ -4,6 +4,17 @@ use colored::Colorize;
 
 pub type Value = i64;
 
+// In the compact encoding, a byte with this bit set is a `PUSH` of the
+// zigzag-encoded value in its low 7 bits.
+pub(crate) const SHORT_PUSH: u8 = 0x80;
+
+/// A single VM instruction.
+///
+/// Binary operators pop the right-hand operand first and the left-hand
+/// operand second, so the left-hand side is the value that was pushed first:
+/// `PUSH a`, `PUSH b`, `SUB` leaves `a - b` on the stack. Comparisons push
+/// `1` if the comparison holds and `0` otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Push(Value),
     Pop,
@@ -12,17 +23,117 @@ pub enum Instruction {
     Jz, 
     Jnz,
     Jmp,
+    /// `addr CALL` jumps to `addr` and pushes the return address onto the call stack
     Call,
+    /// returns to the address on top of the call stack
+    Ret,
+    /// `a b ADD` -> `a + b`
     Add,
+    /// `a b SUB` -> `a - b`
     Sub,
+    /// `a b MUL` -> `a * b`
     Mul,
+    /// `a b DIV` -> `a / b`, rounded towards zero
     Div,
+    /// `a b MOD` -> `a % b`, with the sign of `a`
+    Mod,
+    /// `a b EQ` -> `a == b`
+    Eq,
+    /// `a b NEQ` -> `a != b`
+    Neq,
+    /// `a b LT` -> `a < b`
+    Lt,
+    /// `a b LE` -> `a <= b`
+    Le,
+    /// `a b GT` -> `a > b`
+    Gt,
+    /// `a b GE` -> `a >= b`
+    Ge,
+    /// `a b AND` -> bitwise `a & b`
+    And,
+    /// `a b OR` -> bitwise `a | b`
+    Or,
+    /// `a b XOR` -> bitwise `a ^ b`
+    Xor,
+    /// `a NOT` -> logical negation: `1` if `a == 0`, `0` otherwise
+    Not,
+    /// `a b SHL` -> `a << b`
+    Shl,
+    /// `a b SHR` -> arithmetic (sign-extending) `a >> b`
+    Shr,
+    /// `addr LOAD` -> the 8-byte little-endian word at `addr`
+    Load,
+    /// `value addr STORE` writes `value` as an 8-byte little-endian word to `addr`
+    Store,
+    /// `addr LOAD8` -> the byte at `addr`, zero-extended
+    Load8,
+    /// `value addr STORE8` writes the lowest byte of `value` to `addr`
+    Store8,
     Exit,
     Printout,
     Printstr,
+    /// `addr PRINTMEM` prints the null-terminated string stored at `addr`
+    Printmem,
+    /// reads a single byte from the input, pushes `-1` on end of input
+    Readchar,
+    /// reads a line from the input and parses it as an integer. Pushes the
+    /// value followed by `1`, or `0` followed by `0` on end of input
+    Readint,
+    /// `addr len READLINE` reads a line from the input into memory at `addr`.
+    /// The line is stored without its newline, truncated to `len - 1` bytes and
+    /// null-terminated. Pushes the number of bytes stored, or `-1` on end of input
+    Readline,
+    /// `id SYSCALL` calls the host function registered as `id`
+    Syscall,
 }
 
 impl Instruction {
+    /// Looks up an instruction without argument by its mnemonic. `PUSH`
+    /// yields `Push(0)`.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        Some(match mnemonic {
+            "PUSH" => Self::Push(0),
+            "POP" => Self::Pop,
+            "DUP" => Self::Dup,
+            "SWAP" => Self::Swap,
+            "JZ" => Self::Jz,
+            "JNZ" => Self::Jnz,
+            "JMP" => Self::Jmp,
+            "CALL" => Self::Call,
+            "RET" => Self::Ret,
+            "ADD" => Self::Add,
+            "SUB" => Self::Sub,
+            "MUL" => Self::Mul,
+            "DIV" => Self::Div,
+            "MOD" => Self::Mod,
+            "EQ" => Self::Eq,
+            "NEQ" => Self::Neq,
+            "LT" => Self::Lt,
+            "LE" => Self::Le,
+            "GT" => Self::Gt,
+            "GE" => Self::Ge,
+            "AND" => Self::And,
+            "OR" => Self::Or,
+            "XOR" => Self::Xor,
+            "NOT" => Self::Not,
+            "SHL" => Self::Shl,
+            "SHR" => Self::Shr,
+            "LOAD" => Self::Load,
+            "STORE" => Self::Store,
+            "LOAD8" => Self::Load8,
+            "STORE8" => Self::Store8,
+            "EXIT" => Self::Exit,
+            "PRINTOUT" => Self::Printout,
+            "PRINTSTR" => Self::Printstr,
+            "PRINTMEM" => Self::Printmem,
+            "READCHAR" => Self::Readchar,
+            "READINT" => Self::Readint,
+            "READLINE" => Self::Readline,
+            "SYSCALL" => Self::Syscall,
+            _ => return None
+        })
+    }
+
     pub fn mnemonic(&self) -> &str {
         match self {
             Self::Push(_) => "PUSH",
@@ -33,13 +144,36 @@ impl Instruction {
             Self::Jnz => "JNZ",
             Self::Jmp => "JMP",
             Self::Call => "CALL",
+            Self::Ret => "RET",
             Self::Add => "ADD",
             Self::Sub => "SUB",
             Self::Mul => "MUL",
             Self::Div => "DIV",
+            Self::Mod => "MOD",
+            Self::Eq => "EQ",
+            Self::Neq => "NEQ",
+            Self::Lt => "LT",
+            Self::Le => "LE",
+            Self::Gt => "GT",
+            Self::Ge => "GE",
+            Self::And => "AND",
+            Self::Or => "OR",
+            Self::Xor => "XOR",
+            Self::Not => "NOT",
+            Self::Shl => "SHL",
+            Self::Shr => "SHR",
+            Self::Load => "LOAD",
+            Self::Store => "STORE",
+            Self::Load8 => "LOAD8",
+            Self::Store8 => "STORE8",
             Self::Exit => "EXIT",
             Self::Printout => "PRINTOUT",
             Self::Printstr => "PRINTSTR",
+            Self::Printmem => "PRINTMEM",
+            Self::Readchar => "READCHAR",
+            Self::Readint => "READINT",
+            Self::Readline => "READLINE",
+            Self::Syscall => "SYSCALL",
         }
     }
 
@@ -60,6 +194,29 @@ impl Instruction {
             Self::Exit => 11,
             Self::Printout => 12,
             Self::Printstr => 14,
+            Self::Eq => 15,
+            Self::Neq => 16,
+            Self::Lt => 17,
+            Self::Le => 18,
+            Self::Gt => 19,
+            Self::Ge => 20,
+            Self::And => 21,
+            Self::Or => 22,
+            Self::Xor => 23,
+            Self::Not => 24,
+            Self::Shl => 25,
+            Self::Shr => 26,
+            Self::Mod => 27,
+            Self::Ret => 28,
+            Self::Load => 29,
+            Self::Store => 30,
+            Self::Load8 => 31,
+            Self::Store8 => 32,
+            Self::Printmem => 33,
+            Self::Readchar => 34,
+            Self::Readint => 35,
+            Self::Readline => 36,
+            Self::Syscall => 37,
         }
     }
     
@@ -75,6 +232,40 @@ impl Instruction {
             _ => self.id().to_le_bytes().to_vec()
         }
     }
+
+    /// Encodes the instruction with a 1-byte opcode. `PUSH` of a value in
+    /// `-64..=63` takes a single byte, other values follow the opcode as
+    /// zigzag-encoded LEB128.
+    pub fn as_compact_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Push(arg) => {
+                let mut zigzag = ((arg << 1) ^ (arg >> 63)) as u64;
+                if zigzag < SHORT_PUSH as u64 {
+                    return vec![SHORT_PUSH | zigzag as u8]
+                }
+
+                let mut bytes = vec![self.id() as u8];
+                while zigzag >= 0x80 {
+                    bytes.push(zigzag as u8 | 0x80);
+                    zigzag >>= 7;
+                }
+                bytes.push(zigzag as u8);
+                bytes
+            }
+            _ => vec![self.id() as u8]
+        }
+    }
+}
+
+/// Id of the host function named `name`, as used by `SYSCALL`. Names are
+/// mapped to ids with the 64-bit FNV-1a hash, so the assembler and embedders
+/// agree on ids without sharing a table.
+pub fn syscall_id(name: &str) -> Value {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    name.bytes()
+        .fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME)) as Value
 }
 
 impl fmt::Display for Instruction {
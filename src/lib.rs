@@ -0,0 +1,27 @@
+//! A stack-based virtual machine and its assembler.
+//!
+//! [`AsmParser`] assembles `.stasm` sources into a [`Binary`], which can be
+//! saved to and loaded from `.bin` files and executed by a [`StackMachine`].
+
+mod assembler;
+mod binary;
+mod debug_info;
+mod expr;
+mod instruction;
+mod lint;
+mod linker;
+mod listing;
+mod object;
+mod stack_machine;
+
+pub use assembler::{AsmParser, ParseError, ParseErrors, ParseResult, Span};
+pub use binary::{Binary, Encoding, LoadError, LoadResult, SaveResult, FORMAT_VERSION};
+pub use debug_info::DebugInfo;
+pub use instruction::{syscall_id, Instruction, Value};
+pub use linker::{link, LinkError, LinkErrors};
+pub use lint::{Lint, Warning};
+pub use object::Object;
+pub use stack_machine::{
+    ArithmeticMode, ExecError, ExecResult, StackMachine, Syscall, SyscallContext, DEFAULT_INSTRUCTION_COST,
+    DEFAULT_MAX_CALL_DEPTH, DEFAULT_MEMORY_SIZE,
+};
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, HashSet};
+
+use colored::Colorize;
+
+use crate::{binary::Binary, debug_info::DebugInfo, expr::EvalError, instruction::Value, object::{Location, Object}};
+
+#[derive(Debug)]
+pub enum LinkError {
+    /// two modules export a label with the same name
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String
+    },
+    /// a module refers to a label no module defines
+    UndefinedSymbol {
+        name: String,
+        module: String
+    },
+    /// a relocation could not be evaluated or written
+    Relocation {
+        err: String,
+        module: String
+    },
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = "Link Error:".bold().red();
+        match self {
+            Self::DuplicateSymbol { name, first, second } => write!(f, "{} symbol `{}` is defined by both `{}` and `{}`", prefix, name, first, second),
+            Self::UndefinedSymbol { name, module } => write!(f, "{} undefined symbol `{}` in `{}`", prefix, name, module),
+            Self::Relocation { err, module } => write!(f, "{} {} in `{}`", prefix, err, module),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// All errors found while linking a program.
+#[derive(Debug)]
+pub struct LinkErrors(pub Vec<LinkError>);
+
+impl std::fmt::Display for LinkErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for err in &self.0 {
+            writeln!(f, "{}", err)?;
+        }
+        write!(f, "{} could not link due to {} error{}",
+            "Error:".bold().red(),
+            self.0.len(),
+            if self.0.len() == 1 { "" } else { "s" }
+        )
+    }
+}
+
+impl std::error::Error for LinkErrors {}
+
+/// Links `objects` into a binary. Their code and data are laid out in the
+/// given order, so execution starts with the first object. Labels are looked
+/// up in the referring object first, then among the exported labels of all objects.
+pub fn link(objects: &[Object]) -> Result<Binary, LinkErrors> {
+    let mut errors = vec![];
+
+    // start address of each object's code and data
+    let mut bases = vec![];
+    let (mut code_size, mut data_size) = (0, 0);
+    for object in objects {
+        bases.push((code_size, data_size));
+        code_size += object.instructions().len();
+        data_size += object.data().len();
+    }
+    let address = |module: usize, location: Location| match location {
+        Location::Instruction(addr) => bases[module].0 + addr,
+        Location::Data(offset) => bases[module].1 + offset,
+    };
+
+    let mut exported: HashMap<&str, (Value, usize)> = HashMap::new();
+    for (module, object) in objects.iter().enumerate() {
+        for symbol in object.symbols().iter().filter(|symbol| symbol.exported) {
+            let value = address(module, symbol.location) as Value;
+            if let Some((_, first)) = exported.insert(&symbol.name, (value, module)) {
+                errors.push(LinkError::DuplicateSymbol {
+                    name: symbol.name.clone(),
+                    first: objects[first].name().to_string(),
+                    second: object.name().to_string()
+                });
+            }
+        }
+    }
+
+    let mut instructions: Vec<_> = objects.iter().flat_map(|object| object.instructions().iter().copied()).collect();
+    let mut data: Vec<_> = objects.iter().flat_map(|object| object.data().iter().copied()).collect();
+    let mut debug_info = DebugInfo::default();
+
+    for (module, object) in objects.iter().enumerate() {
+        let mut labels: HashMap<String, Value> = exported.iter()
+            .map(|(name, (value, _))| (name.to_string(), *value))
+            .collect();
+        labels.extend(object.symbols().iter().map(|symbol| (symbol.name.clone(), address(module, symbol.location) as Value)));
+
+        // report every undefined symbol once per module
+        let mut undefined = HashSet::new();
+        for reloc in object.relocs() {
+            let value = match reloc.expr.eval(&HashMap::new(), &labels) {
+                Ok(value) => value,
+                Err(EvalError::Unresolved(name, _)) => {
+                    if undefined.insert(name.clone()) {
+                        errors.push(LinkError::UndefinedSymbol { name, module: object.name().to_string() });
+                    }
+                    continue
+                }
+                Err(EvalError::Invalid(err, _)) => {
+                    errors.push(LinkError::Relocation { err, module: object.name().to_string() });
+                    continue
+                }
+            };
+
+            const WORD_SIZE: usize = std::mem::size_of::<Value>();
+            match reloc.location {
+                Location::Instruction(offset) if offset < object.instructions().len() => {
+                    instructions[address(module, reloc.location)].set_arg(value)
+                }
+                Location::Data(offset) if offset.checked_add(WORD_SIZE).is_some_and(|end| end <= object.data().len()) => {
+                    let addr = address(module, reloc.location);
+                    data[addr..addr + WORD_SIZE].copy_from_slice(&value.to_le_bytes())
+                }
+                _ => errors.push(LinkError::Relocation { err: "relocation outside of the module".to_string(), module: object.name().to_string() })
+            }
+        }
+
+        if let Some(object_debug_info) = object.debug_info() {
+            debug_info.append(object_debug_info, bases[module].0 as i64);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(LinkErrors(errors))
+    }
+
+    let mut binary = Binary::new(instructions, data);
+    if objects.iter().any(|object| object.debug_info().is_some()) {
+        binary.set_debug_info(Some(debug_info));
+    }
+    Ok(binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::{AsmParser, Span}, expr::Expr, instruction::Instruction, object::{Relocation, Symbol}};
+
+    use super::*;
+
+    fn assemble(name: &str, source: &str) -> Object {
+        AsmParser::new(name.to_string())
+            .assemble_object_reader(source.as_bytes())
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn link_sources(sources: &[(&str, &str)]) -> Result<Binary, LinkErrors> {
+        let objects: Vec<_> = sources.iter().map(|(name, source)| assemble(name, source)).collect();
+        link(&objects)
+    }
+
+    #[test]
+    fn resolves_symbols_across_modules() {
+        let binary = link_sources(&[
+            ("main.stasm", "main:\n PUSH double\n CALL\n EXIT\n"),
+            ("lib.stasm", "double:\n PUSH main\n RET\n"),
+        ]).unwrap();
+
+        assert_eq!(binary.instructions(), [
+            Instruction::Push(3), Instruction::Call, Instruction::Exit,
+            Instruction::Push(0), Instruction::Ret,
+        ]);
+    }
+
+    #[test]
+    fn relocates_data_across_modules() {
+        let binary = link_sources(&[
+            ("main.stasm", "@Data first 1\nmain:\n PUSH table\n EXIT\n"),
+            ("lib.stasm", "@Data table main, table + 8\n"),
+        ]).unwrap();
+
+        assert_eq!(binary.instructions()[0], Instruction::Push(8));
+        let words: Vec<_> = binary.data().chunks(8).map(|word| Value::from_le_bytes(word.try_into().unwrap())).collect();
+        assert_eq!(words, [1, 0, 16]);
+    }
+
+    #[test]
+    fn reports_duplicate_symbols() {
+        let err = link_sources(&[
+            ("a.stasm", "f:\n RET\n"),
+            ("b.stasm", "f:\n RET\n"),
+        ]).unwrap_err();
+
+        assert!(matches!(&err.0[..], [LinkError::DuplicateSymbol { name, first, second }]
+            if name == "f" && first == "a.stasm" && second == "b.stasm"));
+    }
+
+    #[test]
+    fn reports_undefined_symbols_once() {
+        let err = link_sources(&[
+            ("main.stasm", "main:\n PUSH missing\n PUSH missing + 1\n EXIT\n"),
+        ]).unwrap_err();
+
+        assert!(matches!(&err.0[..], [LinkError::UndefinedSymbol { name, module }]
+            if name == "missing" && module == "main.stasm"));
+    }
+
+    #[test]
+    fn keeps_local_and_hidden_labels_private() {
+        let module = "f:\n.loop:\n PUSH 1\n @If\n PUSH .loop\n JMP\n @EndIf\n RET\n";
+        // both modules define `f.loop` and hidden `@If` labels, only `f` clashes
+        let err = link_sources(&[("a.stasm", module), ("b.stasm", module)]).unwrap_err();
+        assert!(matches!(&err.0[..], [LinkError::DuplicateSymbol { name, .. }] if name == "f"));
+
+        // only global labels can be imported, so other modules can't refer to `f.loop`
+        let result = AsmParser::new("main.stasm".to_string()).assemble_object_reader("main:\n PUSH f.loop\n EXIT\n".as_bytes());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_relocations_outside_of_the_module() {
+        let reloc = |location| Relocation { location, expr: Expr::Name("f".to_string(), Span::default()) };
+        let symbols = vec![Symbol { name: "f".to_string(), location: Location::Instruction(0), exported: true }];
+        let object = Object::new(
+            "corrupt.o".to_string(),
+            vec![Instruction::Push(0)],
+            vec![0; 8],
+            symbols,
+            vec![reloc(Location::Instruction(1)), reloc(Location::Data(1)), reloc(Location::Data(usize::MAX - 2))]
+        );
+
+        let err = link(&[object]).unwrap_err();
+        assert_eq!(err.0.len(), 3);
+        assert!(err.0.iter().all(|err| matches!(err, LinkError::Relocation { .. })));
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{HashMap, HashSet};
+
+use colored::{Color, Colorize};
+
+use crate::{assembler::{Notes, Span}, debug_info::DebugInfo, instruction::Instruction};
+
+/// A kind of suspicious code reported by the assembler. Each one can be
+/// suppressed for a line with a `; allow(<name>)` comment on it or on its
+/// own line directly above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// a label that is never referenced
+    UnusedLabel,
+    /// instructions following `JMP`, `RET` or `EXIT` that no label leads to
+    UnreachableCode,
+    /// a `@Break` left in the program
+    Breakpoint,
+    /// a label named like a mnemonic
+    ShadowedMnemonic,
+}
+
+impl Lint {
+    pub const ALL: [Self; 4] = [Self::UnusedLabel, Self::UnreachableCode, Self::Breakpoint, Self::ShadowedMnemonic];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::UnusedLabel => "unused-label",
+            Self::UnreachableCode => "unreachable-code",
+            Self::Breakpoint => "breakpoint",
+            Self::ShadowedMnemonic => "shadowed-mnemonic",
+        }
+    }
+}
+
+impl std::str::FromStr for Lint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|lint| lint.name() == s)
+            .ok_or_else(|| format!("unknown lint `{}`, expected one of {}",
+                s,
+                Self::ALL.map(|lint| format!("`{}`", lint.name())).join(", ")
+            ))
+    }
+}
+
+#[derive(Debug)]
+pub struct Warning {
+    pub lint: Lint,
+    pub msg: String,
+    pub span: Span,
+    pub notes: Notes,
+}
+
+impl Warning {
+    /// Formats the warning as an error, for when warnings are denied.
+    pub(crate) fn fmt_denied(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} {}", "Parse Error:".bold().red(), self.msg)?;
+        self.fmt_body(f, Color::Red)?;
+        write!(f, "\n{} `{}` is denied because warnings are treated as errors", "note:".bold().cyan(), self.lint.name())
+    }
+
+    fn fmt_body(&self, f: &mut std::fmt::Formatter<'_>, color: Color) -> std::fmt::Result {
+        self.span.fmt_snippet(f, color)?;
+        for (note, span) in &self.notes {
+            writeln!(f, "\n{} {}", "note:".bold().cyan(), note)?;
+            span.fmt_snippet(f, color)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} {}", "Warning:".bold().yellow(), self.msg)?;
+        self.fmt_body(f, Color::Yellow)?;
+        write!(f, "\n{} add `; allow({})` to suppress this warning", "help:".bold().cyan(), self.lint.name())
+    }
+}
+
+/// Everything the assembler remembers about a program for linting it.
+pub(crate) struct LintInput<'a> {
+    pub instructions: &'a [Instruction],
+    /// where each instruction was written
+    pub instruction_spans: &'a [Span],
+    pub label_defs: &'a HashMap<String, Span>,
+    /// names referenced by any expression
+    pub used_names: &'a HashSet<String>,
+    pub breakpoints: &'a [Span],
+    /// lints allowed on a line, keyed by file and line number
+    pub allowed: &'a HashMap<(String, usize), HashSet<Lint>>,
+    pub debug_info: &'a DebugInfo,
+}
+
+impl LintInput<'_> {
+    pub fn run(&self) -> Vec<Warning> {
+        let mut warnings = vec![];
+        self.unused_labels(&mut warnings);
+        self.unreachable_code(&mut warnings);
+        self.breakpoints(&mut warnings);
+        self.shadowed_mnemonics(&mut warnings);
+
+        warnings.retain(|warning| !self.allowed.get(&(warning.span.file.clone(), warning.span.lineno))
+            .is_some_and(|allowed| allowed.contains(&warning.lint)));
+        warnings.sort_by(|a, b| (&a.span.file, a.span.lineno, a.span.col).cmp(&(&b.span.file, b.span.lineno, b.span.col)));
+        warnings
+    }
+
+    fn warn(lint: Lint, msg: String, span: &Span) -> Warning {
+        Warning { lint, msg, span: span.clone(), notes: vec![] }
+    }
+
+    fn unused_labels(&self, warnings: &mut Vec<Warning>) {
+        // a label defined in a macro is unused if none of its expansions is used
+        let mut defs: HashMap<&Span, (String, bool)> = HashMap::new();
+        for (label, span) in self.label_defs {
+            // name macro labels as written, without the expansion's scope and suffix
+            let name = if label.contains('#') {
+                span.line.chars().skip(span.col - 1).take(span.len).collect()
+            } else {
+                label.clone()
+            };
+            let used = self.used_names.contains(label);
+            defs.entry(span)
+                .and_modify(|(_, any_used)| *any_used |= used)
+                .or_insert((name, used));
+        }
+
+        warnings.extend(defs.into_iter()
+            .filter(|(_, (_, used))| !used)
+            .map(|(span, (name, _))| Self::warn(Lint::UnusedLabel, format!("label `{}` is never used", name), span)));
+    }
+
+    fn unreachable_code(&self, warnings: &mut Vec<Warning>) {
+        // address of the `JMP`, `RET` or `EXIT` that ended the reachable code
+        let mut terminator: Option<usize> = None;
+        // every unreachable block is reported only once, up to the next label
+        let mut unreachable = false;
+        for (addr, (instruction, span)) in self.instructions.iter().zip(self.instruction_spans).enumerate() {
+            if self.debug_info.label_at(addr as i64).is_some() {
+                terminator = None;
+                unreachable = false;
+            }
+            else if unreachable {
+                continue
+            }
+            else if let Some(terminator) = terminator {
+                let mut warning = Self::warn(Lint::UnreachableCode, "unreachable instruction".to_string(), span);
+                warning.notes.push((
+                    "any code following this instruction is unreachable without a label".to_string(),
+                    self.instruction_spans[terminator].clone()
+                ));
+                warnings.push(warning);
+                unreachable = true;
+                continue
+            }
+
+            if matches!(instruction, Instruction::Jmp | Instruction::Ret | Instruction::Exit) {
+                terminator = Some(addr);
+            }
+        }
+    }
+
+    fn breakpoints(&self, warnings: &mut Vec<Warning>) {
+        warnings.extend(self.breakpoints.iter()
+            .map(|span| Self::warn(Lint::Breakpoint, "breakpoint left in the program".to_string(), span)));
+    }
+
+    fn shadowed_mnemonics(&self, warnings: &mut Vec<Warning>) {
+        let mut reported = HashSet::new();
+        for (label, span) in self.label_defs {
+            let name = label.split('#').next().unwrap_or(label);
+            if Instruction::from_mnemonic(&name.to_uppercase()).is_some() && reported.insert(span) {
+                warnings.push(Self::warn(
+                    Lint::ShadowedMnemonic,
+                    format!("label `{}` has the same name as the mnemonic `{}`", name, name.to_uppercase()),
+                    span
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{AsmParser, ParseError, ParseErrors};
+
+    use super::*;
+
+    // lint and line of every warning
+    fn warnings(source: &str) -> Vec<(Lint, usize)> {
+        let mut parser = AsmParser::new("test.stasm".to_string());
+        parser.assemble_reader(source.as_bytes()).unwrap_or_else(|err| panic!("{}", err));
+        parser.warnings().iter().map(|warning| (warning.lint, warning.span.lineno)).collect()
+    }
+
+    #[test]
+    fn unused_label() {
+        assert_eq!(warnings("PUSH used\nJMP\nused:\nunused:\nEXIT"), [(Lint::UnusedLabel, 4)]);
+    }
+
+    #[test]
+    fn unreachable_code() {
+        assert_eq!(warnings("EXIT\nPUSH 1\nPUSH 2\n"), [(Lint::UnreachableCode, 2)]);
+        assert_eq!(warnings("PUSH end\nJMP\nend:\nEXIT"), []);
+    }
+
+    #[test]
+    fn breakpoint() {
+        assert_eq!(warnings("@Break\nEXIT"), [(Lint::Breakpoint, 1)]);
+    }
+
+    #[test]
+    fn shadowed_mnemonic() {
+        assert_eq!(warnings("PUSH push\nJMP\npush:\nEXIT"), [(Lint::ShadowedMnemonic, 3)]);
+    }
+
+    #[test]
+    fn allow_comments() {
+        assert_eq!(warnings("@Break ; allow(breakpoint)\nEXIT"), []);
+        assert_eq!(warnings("; allow(breakpoint)\n@Break\nEXIT"), []);
+        // only the next line is covered
+        assert_eq!(warnings("; allow(breakpoint)\n@Break\n@Break\nEXIT"), [(Lint::Breakpoint, 3)]);
+        assert_eq!(warnings("EXIT\nPUSH 1 ; allow(unreachable-code, breakpoint)"), []);
+    }
+
+    #[test]
+    fn unknown_lint() {
+        let Err(ParseErrors(errors)) = AsmParser::new("test.stasm".to_string()).assemble_reader("EXIT ; allow(nope)".as_bytes()) else {
+            panic!("accepted an unknown lint")
+        };
+        assert!(matches!(&errors[..], [ParseError::Parse { err, .. }] if err.starts_with("unknown lint `nope`")));
+    }
+
+    #[test]
+    fn denied_warnings() {
+        let mut parser = AsmParser::new("test.stasm".to_string());
+        parser.set_deny_warnings(true);
+        let Err(ParseErrors(errors)) = parser.assemble_reader("@Break\nEXIT".as_bytes()) else {
+            panic!("warnings were not denied")
+        };
+        assert!(matches!(&errors[..], [ParseError::Denied(warning)] if warning.lint == Lint::Breakpoint));
+    }
+}
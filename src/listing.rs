@@ -0,0 +1,103 @@
+use std::{io::Write, ops::Range};
+
+use crate::binary::Binary;
+
+// data bytes shown per row of the listing
+const BYTES_PER_ROW: usize = 10;
+
+/// What a single source line assembled to.
+pub(crate) struct ListingLine {
+    pub file: String,
+    pub lineno: usize,
+    pub line: String,
+    /// label defined on this line
+    pub label: Option<String>,
+    /// address of the next instruction when the line was reached
+    pub addr: usize,
+    /// addresses of the instructions the line produced
+    pub code: Range<usize>,
+    /// offsets of the bytes the line added to the data section
+    pub data: Range<usize>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Writes every line with its address, encoded bytes and label.
+/// Lines producing several instructions or more data than fits a row are
+/// continued on the following rows.
+pub(crate) fn write_listing(lines: &[ListingLine], binary: &Binary, out: &mut dyn Write) -> std::io::Result<()> {
+    let mut file = None;
+    for line in lines {
+        if file != Some(&line.file) {
+            writeln!(out, "{}; {}", if file.is_some() { "\n" } else { "" }, line.file)?;
+            file = Some(&line.file);
+        }
+
+        let code = line.code.clone()
+            .map(|addr| (format!("{:04x}", addr), binary.encoding().encode(&binary.instructions()[addr])));
+        let data = binary.data()[line.data.clone()]
+            .chunks(BYTES_PER_ROW)
+            .enumerate()
+            .map(|(i, chunk)| (format!("d:{:04x}", line.data.start + i * BYTES_PER_ROW), chunk.to_vec()));
+        let mut rows = code.chain(data);
+
+        // lines without code or data show the address a label on them resolves to
+        let (addr, bytes) = rows.next().unwrap_or_else(|| (format!("{:04x}", line.addr), vec![]));
+        let row = format!("{:<6} {:<32} {:<16} {:>4}  {}", addr, hex(&bytes), line.label.as_deref().unwrap_or(""), line.lineno, line.line);
+        writeln!(out, "{}", row.trim_end())?;
+        for (addr, bytes) in rows {
+            writeln!(out, "{:<6} {}", addr, hex(&bytes))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::AsmParser;
+
+    fn listing(mut parser: AsmParser, source: Option<&str>) -> Vec<String> {
+        let binary = match source {
+            Some(source) => parser.assemble_reader(source.as_bytes()),
+            None => parser.assemble()
+        }.unwrap();
+        let mut out = vec![];
+        parser.write_listing(&binary, &mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn code_data_and_label_rows() {
+        let source = "@String msg \"hello world!\"\nmain:\n    @PushStr \"ab\"\n    PUSH msg\n    EXIT\n";
+        assert_eq!(listing(AsmParser::new("test.stasm".to_string()), Some(source)), [
+            "; test.stasm",
+            "d:0000 68 65 6c 6c 6f 20 77 6f 72 6c    msg                 1  @String msg \"hello world!\"",
+            "d:000a 64 21 00",
+            "0000                                    main                2  main:",
+            "0000   00 00 00 00 00 00 00 00 00 00                        3      @PushStr \"ab\"",
+            "0001   00 00 62 00 00 00 00 00 00 00",
+            "0002   00 00 61 00 00 00 00 00 00 00",
+            "0003   00 00 00 00 00 00 00 00 00 00                        4      PUSH msg",
+            "0004   0b 00                                                5      EXIT",
+        ]);
+    }
+
+    #[test]
+    fn include_line_shows_the_start_of_the_included_code() {
+        let dir = std::env::temp_dir().join(format!("stackvm-listing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.stasm"), "f:\n RET\n").unwrap();
+        std::fs::write(dir.join("main.stasm"), "PUSH f\nCALL\n@Include \"lib.stasm\"\nEXIT\n").unwrap();
+
+        let lines = listing(AsmParser::new(dir.join("main.stasm").display().to_string()), None);
+        let include = lines.iter().find(|line| line.ends_with("@Include \"lib.stasm\"")).unwrap();
+        assert!(include.starts_with("0002 "), "{}", include);
+        assert!(lines.iter().any(|line| line.starts_with("0003   0b 00") && line.ends_with("EXIT")));
+    }
+}
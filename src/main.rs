@@ -1,69 +1,216 @@
-#![feature(let_chains)]
+use std::{fs::File, io::{BufReader, BufWriter, Write}};
 
-mod assembler;
-mod binary;
-mod debug_info;
-mod instruction;
-mod stack_machine;
-
-use assembler::*;
-use stack_machine::*;
-
-use crate::{binary::Binary, debug_info::DebugInfo};
+use stackvm::*;
 
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    filepath: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// input file, `-` reads assembly source or a binary from stdin
+    #[arg(required = true)]
+    filepath: Option<String>,
 
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     run: bool,
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     assemble: bool,
+    /// assemble into an object file to be linked with `link`
+    #[arg(short = 'c', long, action = clap::ArgAction::SetTrue, requires_all = ["assemble", "output_filepath"], conflicts_with = "run")]
+    object: bool,
     #[arg(short, long, action = clap::ArgAction::SetTrue)]
     verbose: bool,
+    /// overflow semantics of arithmetic instructions: `wrapping`, `checked` or `saturating`
+    #[arg(long, default_value = "checked")]
+    arithmetic: ArithmeticMode,
+    /// maximum number of nested `CALL`s
+    #[arg(long, default_value_t = DEFAULT_MAX_CALL_DEPTH)]
+    max_call_depth: usize,
+    /// size of the linear memory in bytes
+    #[arg(long, default_value_t = DEFAULT_MEMORY_SIZE)]
+    memory_size: usize,
+    /// stop execution after spending this much fuel (one unit per instruction)
+    #[arg(long)]
+    fuel: Option<u64>,
+    /// read program input from a file instead of stdin
+    #[arg(long)]
+    input: Option<String>,
+    /// write program output to a file instead of stdout
+    #[arg(long)]
+    output: Option<String>,
 
     #[arg(short)]
-    output_filepath: Option<String>
+    output_filepath: Option<String>,
+    /// directory searched by `@Include`, may be given multiple times
+    #[arg(short = 'I')]
+    include_dirs: Vec<String>,
+    /// write an assembler listing to this file
+    #[arg(short = 'l', conflicts_with = "object")]
+    listing_filepath: Option<String>,
+    /// don't save labels, breakpoints and source locations in the binary
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    strip: bool,
+    /// write the code with 1-byte opcodes and variable-length `PUSH` arguments
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "object")]
+    compact: bool,
+    /// fail to assemble if there are lint warnings
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    deny_warnings: bool,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// link object files into a binary
+    Link {
+        /// object files, laid out in the given order
+        #[arg(required = true)]
+        objects: Vec<String>,
+        #[arg(short)]
+        output_filepath: String,
+        /// don't save labels, breakpoints and source locations in the binary
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        strip: bool,
+        /// write the code with 1-byte opcodes and variable-length `PUSH` arguments
+        #[arg(long, action = clap::ArgAction::SetTrue)]
+        compact: bool,
+    },
 }
 
 fn main() {
     use clap::Parser;
     let args = Cli::parse();
 
-    let instructions;
+    if let Some(Command::Link { objects, output_filepath, strip, compact }) = args.command {
+        let objects: Vec<_> = objects.into_iter()
+            .map(|filepath| Object::load_from(filepath).unwrap_or_else(|err| die(err)))
+            .collect();
+        let mut binary = link(&objects).unwrap_or_else(|err| die(err));
+        if strip {
+            binary.set_debug_info(None);
+        }
+        if compact {
+            binary.set_encoding(Encoding::Compact);
+        }
+        if let Err(err) = binary.save_to(output_filepath) {
+            die(err);
+        }
+        return
+    }
+
+    let filepath = args.filepath.unwrap_or_default();
+
+    let mut binary: Binary;
     let mut debug_info;
     if args.assemble {
-        let mut parser = AsmParser::new(args.filepath);
-        match parser.assemble() {
+        let from_stdin = filepath == "-";
+        let mut parser = AsmParser::new(if from_stdin { "<stdin>".to_string() } else { filepath });
+        for dir in args.include_dirs {
+            parser.add_include_dir(dir);
+        }
+        parser.set_deny_warnings(args.deny_warnings);
+
+        if args.object {
+            let result = if from_stdin {
+                parser.assemble_object_reader(std::io::stdin().lock())
+            }
+            else {
+                parser.assemble_object()
+            };
+            let mut object = result.unwrap_or_else(|err| die(err));
+            for warning in parser.warnings() {
+                eprintln!("{}\n", warning);
+            }
+            if !args.strip {
+                object.set_debug_info(Some(parser.debug_info()));
+            }
+            if let Some(filepath) = args.output_filepath {
+                if let Err(err) = object.save_to(filepath) {
+                    die(err);
+                }
+            }
+            return
+        }
+
+        let result = if from_stdin {
+            parser.assemble_reader(std::io::stdin().lock())
+        }
+        else {
+            parser.assemble()
+        };
+        match result {
             Err(err) => die(err),
-            Ok(inst) => instructions = inst
+            Ok(bin) => binary = bin
+        }
+        if args.compact {
+            binary.set_encoding(Encoding::Compact);
+        }
+        for warning in parser.warnings() {
+            eprintln!("{}\n", warning);
+        }
+        if let Some(filepath) = args.listing_filepath {
+            let mut out = BufWriter::new(File::create(filepath).unwrap_or_else(|err| die(err)));
+            if let Err(err) = parser.write_listing(&binary, &mut out).and_then(|_| out.flush()) {
+                die(err)
+            }
         }
         debug_info = parser.debug_info();
+        if !args.strip {
+            binary.set_debug_info(Some(debug_info.clone()));
+        }
     }
     else {
-        match Binary::load_from(args.filepath) {
+        let result = if filepath == "-" {
+            Binary::read_from(std::io::stdin().lock())
+        }
+        else {
+            Binary::load_from(filepath)
+        };
+        match result {
             Err(err) => die(err),
-            Ok(binary) => instructions = binary.instructions()
+            Ok(bin) => binary = bin
         }
-        debug_info = DebugInfo::default();
+        debug_info = binary.debug_info().cloned().unwrap_or_default();
     }
 
     debug_info.set_verbose(args.verbose);
 
     if args.run {
         let mut machine = StackMachine::new(debug_info);
-        match machine.run(&instructions) {
+        machine.set_arithmetic_mode(args.arithmetic);
+        machine.set_max_call_depth(args.max_call_depth);
+        machine.set_memory_size(args.memory_size);
+        machine.set_fuel(args.fuel);
+        if let Some(filepath) = args.input {
+            machine.set_input(BufReader::new(File::open(filepath).unwrap_or_else(|err| die(err))));
+        }
+        if let Some(filepath) = args.output {
+            machine.set_output(BufWriter::new(File::create(filepath).unwrap_or_else(|err| die(err))));
+        }
+        if let Err(err) = machine.load_data(binary.data()) {
+            die(err)
+        }
+        match machine.run(binary.instructions()) {
             Ok(exit_code) => println!("[simulation exited with code {}]", exit_code),
+            Err(err @ ExecError::OutOfFuel { .. }) => {
+                eprintln!("{}", err);
+                std::process::exit(OUT_OF_FUEL_EXIT_CODE);
+            }
             Err(err) => die(err)
         }
     }
-    else if let Some(filepath) = args.output_filepath &&
-            let Err(err) = Binary::from_instructions(instructions).save_to(filepath) {
-        die(err);
+    else if let Some(filepath) = args.output_filepath {
+        if let Err(err) = binary.save_to(filepath) {
+            die(err);
+        }
     }
 }
 
+// exit code when `--fuel` runs out, so that scripts can tell it apart from
+// errors (1) and invalid arguments (2)
+const OUT_OF_FUEL_EXIT_CODE: i32 = 3;
+
 fn die(err: impl std::fmt::Display) -> ! {
     eprintln!("{}", err);
     std::process::exit(1);
@@ -0,0 +1,197 @@
+use std::{fs::File, io::{BufWriter, Write}};
+
+use crate::{
+    binary::{self, read_array, read_str, write_str, Encoding, LoadError, LoadResult, SaveResult, SectionKind, FLAG_OBJECT},
+    debug_info::DebugInfo,
+    expr::Expr,
+    instruction::Instruction
+};
+
+/// A position in the code or data of a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Location {
+    /// the instruction at this address
+    Instruction(usize),
+    /// the byte at this offset of the data section
+    Data(usize),
+}
+
+impl Location {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let (tag, offset) = match self {
+            Self::Instruction(addr) => (0u8, addr),
+            Self::Data(offset) => (1u8, offset),
+        };
+        bytes.push(tag);
+        bytes.extend((*offset as u64).to_le_bytes());
+    }
+
+    // labels may point just past the end, e.g. a label on the last line
+    fn within(&self, instructions: &[Instruction], data: &[u8]) -> bool {
+        match self {
+            Self::Instruction(addr) => *addr <= instructions.len(),
+            Self::Data(offset) => *offset <= data.len(),
+        }
+    }
+
+    fn decode(bytes: &mut &[u8]) -> LoadResult<Self> {
+        let [tag] = read_array(bytes)?;
+        let offset = usize::try_from(u64::from_le_bytes(read_array(bytes)?))
+            .map_err(|_| LoadError::Corrupted("location out of range".to_string()))?;
+        match tag {
+            0 => Ok(Self::Instruction(offset)),
+            1 => Ok(Self::Data(offset)),
+            _ => Err(LoadError::Corrupted(format!("unknown location kind {}", tag)))
+        }
+    }
+}
+
+/// A label defined by an object file.
+pub(crate) struct Symbol {
+    pub name: String,
+    pub location: Location,
+    /// whether other modules can refer to it, otherwise it is local to the module
+    pub exported: bool,
+}
+
+/// An expression referring to labels, whose value the linker writes to `location`.
+pub(crate) struct Relocation {
+    pub location: Location,
+    pub expr: Expr,
+}
+
+/// An assembled module, whose labels get their final addresses when it is
+/// linked with other modules by `link`.
+pub struct Object {
+    name: String,
+    instructions: Vec<Instruction>,
+    data: Vec<u8>,
+    symbols: Vec<Symbol>,
+    relocs: Vec<Relocation>,
+    debug_info: Option<DebugInfo>,
+}
+
+impl Object {
+    pub(crate) fn new(name: String, instructions: Vec<Instruction>, data: Vec<u8>, symbols: Vec<Symbol>, relocs: Vec<Relocation>) -> Self {
+        Self {
+            name,
+            instructions,
+            data,
+            symbols,
+            relocs,
+            debug_info: None
+        }
+    }
+
+    /// Name of the module in diagnostics, the path it was assembled from or loaded from.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    pub(crate) fn relocs(&self) -> &[Relocation] {
+        &self.relocs
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Sets the debug info saved with the object, `None` strips it.
+    pub fn set_debug_info(&mut self, debug_info: Option<DebugInfo>) {
+        self.debug_info = debug_info;
+    }
+
+    pub fn load_from(filepath: String) -> LoadResult<Object> {
+        let bytes = std::fs::read(&filepath)?;
+        Self::decode(filepath, &bytes)
+    }
+
+    pub fn save_to(self, filepath: String) -> SaveResult<()> {
+        let file = File::create(filepath)?;
+        let mut writer = BufWriter::new(file);
+
+        writer.write_all(&self.encode())?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // The symbol section holds a u32 count and (name, location, exported: u8)
+    // for each symbol, the relocation section a u32 count and (location, expression)
+    // for each relocation.
+    fn encode(&self) -> Vec<u8> {
+        let code = binary::encode_code(&self.instructions, Encoding::Fixed);
+
+        let mut symbols = vec![];
+        symbols.extend((self.symbols.len() as u32).to_le_bytes());
+        for symbol in &self.symbols {
+            write_str(&mut symbols, &symbol.name);
+            symbol.location.encode(&mut symbols);
+            symbols.push(symbol.exported as u8);
+        }
+
+        let mut relocs = vec![];
+        relocs.extend((self.relocs.len() as u32).to_le_bytes());
+        for reloc in &self.relocs {
+            reloc.location.encode(&mut relocs);
+            reloc.expr.encode(&mut relocs);
+        }
+
+        let debug = self.debug_info.as_ref().map(DebugInfo::encode);
+        let mut sections = vec![
+            (SectionKind::Code, code.as_slice()),
+            (SectionKind::Data, self.data.as_slice()),
+            (SectionKind::Symbols, symbols.as_slice()),
+            (SectionKind::Relocations, relocs.as_slice()),
+        ];
+        if let Some(debug) = &debug {
+            sections.push((SectionKind::Debug, debug));
+        }
+        binary::encode_sections(FLAG_OBJECT, &sections)
+    }
+
+    fn decode(name: String, bytes: &[u8]) -> LoadResult<Object> {
+        let (flags, sections) = binary::decode_sections(bytes)?;
+        if flags & FLAG_OBJECT == 0 {
+            return Err(LoadError::NotAnObject)
+        }
+
+        let instructions = binary::decode_code(binary::required_section(&sections, SectionKind::Code)?, Encoding::from_flags(flags))?;
+        let data = binary::required_section(&sections, SectionKind::Data)?.to_vec();
+
+        let mut reader = binary::required_section(&sections, SectionKind::Symbols)?;
+        let mut symbols = vec![];
+        for _ in 0..u32::from_le_bytes(read_array(&mut reader)?) {
+            let name = read_str(&mut reader)?;
+            let location = Location::decode(&mut reader)?;
+            if !location.within(&instructions, &data) {
+                return Err(LoadError::Corrupted(format!("symbol `{}` is outside of the module", name)))
+            }
+            let [exported] = read_array(&mut reader)?;
+            symbols.push(Symbol { name, location, exported: exported != 0 });
+        }
+
+        let mut reader = binary::required_section(&sections, SectionKind::Relocations)?;
+        let mut relocs = vec![];
+        for _ in 0..u32::from_le_bytes(read_array(&mut reader)?) {
+            let location = Location::decode(&mut reader)?;
+            relocs.push(Relocation { location, expr: Expr::decode(&mut reader)? });
+        }
+
+        let mut object = Object::new(name, instructions, data, symbols, relocs);
+        object.set_debug_info(sections.get(&SectionKind::Debug).map(|debug| DebugInfo::decode(debug)).transpose()?);
+        Ok(object)
+    }
+}
@@ -1,4 +1,4 @@
-use std::{error::Error, io::Write};
+use std::{collections::HashMap, error::Error, io::{Write, Read}};
 
 use colored::Colorize;
 use crate::{instruction::*, debug_info::DebugInfo};
@@ -6,55 +6,279 @@ use crate::{instruction::*, debug_info::DebugInfo};
 pub type ExecResult<T> = Result<T, ExecError>;
 
 #[derive(Debug)]
-pub struct ExecError {
-    addr: usize,
-    err: String
+pub enum ExecError {
+    Panic {
+        addr: usize,
+        err: String
+    },
+    /// The fuel budget did not cover the instruction at `addr`. The machine
+    /// is left intact and resumes at `addr` when run again with more fuel.
+    OutOfFuel {
+        addr: usize,
+        required: u64,
+        remaining: u64
+    }
+}
+
+impl ExecError {
+    /// Address of the instruction at which execution stopped.
+    pub fn addr(&self) -> usize {
+        match self {
+            Self::Panic { addr, .. } | Self::OutOfFuel { addr, .. } => *addr
+        }
+    }
 }
 
 impl std::fmt::Display for ExecError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} (@{}): {}", "Panic:".bold().red(), format!("{:04x}", self.addr).blue(), self.err)
+        let addr = format!("{:04x}", self.addr()).blue();
+        match self {
+            Self::Panic { err, .. } => write!(f, "{} (@{}): {}", "Panic:".bold().red(), addr, err),
+            Self::OutOfFuel { required, remaining, .. } => write!(f,
+                "{} (@{}): next instruction costs {} fuel, {} remaining",
+                "Out of fuel:".bold().yellow(),
+                addr,
+                required,
+                remaining
+            )
+        }
     }
 }
 
 impl Error for ExecError {}
 
-fn print_header(header: &str, width: usize) {
-    println!();
+fn print_header(out: &mut dyn Write, header: &str, width: usize) -> std::io::Result<()> {
+    writeln!(out)?;
     let padding_size = width / 2 - header.len() / 2 - 1;
     let padding = ":".repeat(padding_size);
-    println!(
+    writeln!(
+        out,
         "{}",
         format!("{} {} {}", padding, header, padding).bold().white()
-    );
-    println!();
+    )?;
+    writeln!(out)
+}
+
+// reads a line without its line ending byte by byte, `None` on end of input
+fn read_line(input: &mut dyn Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    let mut byte = [0u8];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) if line.is_empty() => return Ok(None),
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err)
+        }
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Overflow semantics of the arithmetic instructions. Division or modulo by
+/// zero panics regardless of the selected mode.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    /// Results wrap around at the boundary of `Value`. Shift amounts are
+    /// taken modulo the bit width.
+    Wrapping,
+    /// Overflows and out-of-range shift amounts panic.
+    #[default]
+    Checked,
+    /// Results are clamped to `Value::MIN` or `Value::MAX`, including `SHL`
+    /// shifting out set bits. `SHR` by an out-of-range amount shifts out every bit.
+    Saturating,
+}
+
+impl std::str::FromStr for ArithmeticMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrapping" => Ok(Self::Wrapping),
+            "checked" => Ok(Self::Checked),
+            "saturating" => Ok(Self::Saturating),
+            _ => Err(format!("unknown arithmetic mode `{}`, expected one of `wrapping`, `checked`, `saturating`", s))
+        }
+    }
 }
 
-pub struct StackMachine {
+/// A host function callable with `SYSCALL`. It pops its arguments from and
+/// pushes its results onto the stack, and may access memory, e.g. to read a
+/// string passed by address. Returning an error panics the VM.
+pub type Syscall<'io> = Box<dyn FnMut(&mut SyscallContext) -> Result<(), String> + 'io>;
+
+/// The parts of the VM a host function can access.
+pub struct SyscallContext<'vm> {
+    pub stack: &'vm mut Vec<Value>,
+    pub memory: &'vm mut [u8],
+}
+
+impl SyscallContext<'_> {
+    /// Pops an argument, failing if the stack is empty.
+    pub fn pop(&mut self) -> Result<Value, String> {
+        self.stack.pop().ok_or_else(|| "not enough arguments on stack".to_string())
+    }
+
+    pub fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    /// Reads the null-terminated string stored at `addr`, as written by `@String`.
+    pub fn read_str(&self, addr: Value) -> Result<String, String> {
+        let bytes = usize::try_from(addr).ok()
+            .and_then(|start| self.memory.get(start..))
+            .ok_or_else(|| format!("address {} is out of bounds", addr))?;
+        let len = bytes.iter().position(|byte| *byte == 0)
+            .ok_or_else(|| format!("string at address {} is not null-terminated", addr))?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
+
+pub const DEFAULT_INSTRUCTION_COST: u64 = 1;
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+pub const DEFAULT_MEMORY_SIZE: usize = 0x10000;
+
+const MEMORY_VIEW_WIDTH: usize = 16;
+
+/// The virtual machine. Program input and output as well as the debug
+/// console used for breakpoints and the verbose dump are pluggable streams
+/// that default to stdin and stdout.
+pub struct StackMachine<'io> {
     instruction_ptr: usize,
     stack: Vec<Value>,
+    call_stack: Vec<usize>,
+    max_call_depth: usize,
+    memory: Vec<u8>,
+    arithmetic_mode: ArithmeticMode,
+    syscalls: HashMap<Value, Syscall<'io>>,
+    fuel: Option<u64>,
+    instruction_costs: HashMap<u16, u64>,
 
     exited: Option<i32>,
 
+    // I/O
+    input: Box<dyn Read + 'io>,
+    output: Box<dyn Write + 'io>,
+
     // debugging
     term_width: u16,
     debug_info: DebugInfo,
+    console_input: Box<dyn Read + 'io>,
+    console_output: Box<dyn Write + 'io>,
 }
 
-impl StackMachine {
+impl<'io> StackMachine<'io> {
     pub fn new(debug_info: DebugInfo) -> Self {
         let term_size = termsize::get().unwrap_or(termsize::Size { rows: 25, cols: 80 });
         Self {
             instruction_ptr: 0usize,
             stack: vec![],
+            call_stack: vec![],
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            memory: vec![0; DEFAULT_MEMORY_SIZE],
+            arithmetic_mode: ArithmeticMode::default(),
+            syscalls: HashMap::new(),
+            fuel: None,
+            instruction_costs: HashMap::new(),
             exited: None,
+            input: Box::new(std::io::stdin()),
+            output: Box::new(std::io::stdout()),
             term_width: term_size.cols,
-            debug_info
+            debug_info,
+            console_input: Box::new(std::io::stdin()),
+            console_output: Box::new(std::io::stdout()),
+        }
+    }
+
+    /// Limits execution to `fuel` units, charged per instruction according to
+    /// the cost table. `None` removes the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Remaining fuel, `None` if execution is unlimited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Sets the fuel cost of every instruction of the same variant as
+    /// `instruction`, ignoring its argument. Defaults to `DEFAULT_INSTRUCTION_COST`.
+    pub fn set_instruction_cost(&mut self, instruction: &Instruction, cost: u64) {
+        self.instruction_costs.insert(instruction.id(), cost);
+    }
+
+    pub fn instruction_cost(&self, instruction: &Instruction) -> u64 {
+        self.instruction_costs.get(&instruction.id()).copied().unwrap_or(DEFAULT_INSTRUCTION_COST)
+    }
+
+    /// Registers a host function under the id of `name` (see `syscall_id`),
+    /// as called by `@Syscall name`. Returns the id.
+    pub fn register_syscall(&mut self, name: &str, syscall: impl FnMut(&mut SyscallContext) -> Result<(), String> + 'io) -> Value {
+        let id = syscall_id(name);
+        self.register_syscall_id(id, syscall);
+        id
+    }
+
+    /// Registers a host function under a numeric id, replacing any function
+    /// previously registered with the same id.
+    pub fn register_syscall_id(&mut self, id: Value, syscall: impl FnMut(&mut SyscallContext) -> Result<(), String> + 'io) {
+        self.syscalls.insert(id, Box::new(syscall));
+    }
+
+    /// Sets the stream read by `READCHAR`, `READINT` and `READLINE`.
+    pub fn set_input(&mut self, input: impl Read + 'io) {
+        self.input = Box::new(input);
+    }
+
+    /// Sets the stream written by `PRINTOUT`, `PRINTSTR` and `PRINTMEM`.
+    pub fn set_output(&mut self, output: impl Write + 'io) {
+        self.output = Box::new(output);
+    }
+
+    /// Sets the streams of the debug console, which prompts at breakpoints
+    /// and shows the verbose dump.
+    pub fn set_console(&mut self, input: impl Read + 'io, output: impl Write + 'io) {
+        self.console_input = Box::new(input);
+        self.console_output = Box::new(output);
+    }
+
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    /// Resizes the linear memory to `size` bytes. New bytes are zeroed.
+    pub fn set_memory_size(&mut self, size: usize) {
+        self.memory.resize(size, 0);
+    }
+
+    /// Copies a binary's data section to the start of memory.
+    pub fn load_data(&mut self, data: &[u8]) -> ExecResult<()> {
+        if data.len() > self.memory.len() {
+            return Err(self.panic(format!(
+                "data section of {} bytes does not fit into memory of {} bytes",
+                data.len(), self.memory.len()
+            )))
         }
+
+        self.memory[..data.len()].copy_from_slice(data);
+        Ok(())
     }
 
-    fn disassembly(&self, instructions: &[Instruction]) {
-        print_header("Instructions", self.term_width as usize);
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
+
+    fn disassembly(&mut self, instructions: &[Instruction]) -> std::io::Result<()> {
+        let out = &mut self.console_output;
+        let width = self.term_width as usize;
+
+        print_header(out, "Instructions", width)?;
         for (addr, instruction) in instructions.iter().enumerate() {
             let ip_marker = if addr == self.instruction_ptr {
                 ">>"
@@ -62,44 +286,106 @@ impl StackMachine {
                 "  "
             }
             .green();
-            print!(
+            write!(
+                out,
                 "{:<5}{} {}",
                 format!("{:04x}", addr).blue(),
                 ip_marker,
                 instruction
-            );
+            )?;
 
             if let Some(label) = self.debug_info.label_at(addr as i64) {
-                print!("\t{}", format!("; {}", label).bright_black())
+                write!(out, "\t{}", format!("; {}", label).bright_black())?
             }
 
-            println!()
+            if let Some((file, lineno)) = self.debug_info.location_at(addr as i64) {
+                write!(out, "\t{}", format!("({}:{})", file, lineno).bright_black())?
+            }
+
+            writeln!(out)?
         }
 
-        print_header("Stack", self.term_width as usize);
+        print_header(out, "Stack", width)?;
 
         if self.stack.is_empty() {
-            println!("{}\n", "<no entries>".bright_black())
+            writeln!(out, "{}\n", "<no entries>".bright_black())?
         }
         for (addr, value) in self.stack.iter().enumerate() {
-            print!(
+            write!(
+                out,
                 "{:<6}{}",
                 format!("{:04x}", addr).blue(),
                 format!("{}", value).red()
-            );
+            )?;
 
             let ch = *value as u8 as char;
             if *value >= 0 && *value <= u8::MAX as i64 && is_printable(ch) {
-                print!("\t{:?}", ch)
+                write!(out, "\t{:?}", ch)?
+            }
+
+            writeln!(out)?
+        }
+
+        print_header(out, "Memory", width)?;
+
+        // rows that are entirely zero are skipped
+        let mut rows = self.memory.chunks(MEMORY_VIEW_WIDTH)
+            .enumerate()
+            .filter(|(_, row)| row.iter().any(|byte| *byte != 0))
+            .peekable();
+        if rows.peek().is_none() {
+            writeln!(out, "{}\n", format!("<{} bytes, all zero>", self.memory.len()).bright_black())?
+        }
+        for (row, bytes) in rows {
+            write!(out, "{:<6}", format!("{:04x}", row * MEMORY_VIEW_WIDTH).blue())?;
+            for byte in bytes {
+                write!(out, "{} ", format!("{:02x}", byte).red())?;
+            }
+
+            let text: String = bytes.iter()
+                .map(|byte| if is_printable(*byte as char) { *byte as char } else { '.' })
+                .collect();
+            writeln!(out, "\t{}", text.bright_black())?
+        }
+
+        print_header(out, "Call Stack", width)?;
+
+        if self.call_stack.is_empty() {
+            writeln!(out, "{}\n", "<no entries>".bright_black())?
+        }
+        for (depth, return_addr) in self.call_stack.iter().enumerate().rev() {
+            write!(
+                out,
+                "{:<6}{} {}",
+                format!("#{}", depth).blue(),
+                "return to".bright_black(),
+                format!("{:04x}", return_addr).blue()
+            )?;
+
+            if let Some(label) = self.debug_info.label_at(*return_addr as i64) {
+                write!(out, "\t{}", format!("; {}", label).bright_black())?
             }
 
-            println!()
+            writeln!(out)?
         }
+
+        out.flush()
     }
 
     pub fn run(&mut self, instructions: &[Instruction]) -> ExecResult<i32> {
+        let result = self.execute(instructions);
+
+        // flush on every exit path, the caller may exit the process on an error
+        let flushed = self.output.flush().map_err(|err| self.io_error("output", err))
+            .and_then(|_| self.console_output.flush().map_err(|err| self.io_error("debug console", err)));
+        let exit_code = result?;
+        flushed?;
+        Ok(exit_code)
+    }
+
+    fn execute(&mut self, instructions: &[Instruction]) -> ExecResult<i32> {
         if self.debug_info.verbose() {
-            self.disassembly(instructions);
+            self.disassembly(instructions).map_err(|err| self.io_error("debug console", err))?;
         }
 
         while self.exited.is_none() && self.instruction_ptr < instructions.len() {
@@ -111,26 +397,69 @@ impl StackMachine {
 
     fn panic(&mut self, err: String) -> ExecError {
         self.exited = Some(255);
-        ExecError {
+        ExecError::Panic {
             addr: self.instruction_ptr,
             err
         }
     }
 
+    fn io_error(&mut self, stream: &str, err: std::io::Error) -> ExecError {
+        self.panic(format!("could not access {}: {}", stream, err))
+    }
+
     fn pop_stack(&mut self, mnemonic: &str) -> ExecResult<Value> {
         self.stack.pop().ok_or_else(|| self.panic(format!("not enough values on stack for `{}`", mnemonic)))
     }
 
+    // returns the memory range `addr..addr + len` or panics if it is out of bounds
+    fn memory_range(&mut self, addr: Value, len: usize, mnemonic: &str) -> ExecResult<std::ops::Range<usize>> {
+        usize::try_from(addr).ok()
+            .and_then(|start| Some(start..start.checked_add(len)?))
+            .filter(|range| range.end <= self.memory.len())
+            .ok_or_else(|| self.panic(format!(
+                "out-of-bounds memory access in `{}`: address {} (memory size is {} bytes)",
+                mnemonic, addr, self.memory.len()
+            )))
+    }
+
+    // pops `b` (top of stack), then `a` and pushes `a <op> b`
     fn bin_op(&mut self, op: &Instruction) -> ExecResult<()> {
-        let a = self.pop_stack(op.mnemonic())?;
         let b = self.pop_stack(op.mnemonic())?;
+        let a = self.pop_stack(op.mnemonic())?;
 
         use Instruction as I;
+        if matches!(op, I::Div | I::Mod) && b == 0 {
+            return Err(self.panic(format!("division by zero in `{}`", op.mnemonic())))
+        }
+
+        // shift amounts outside of `u32` are out of range in any case
+        let shift = u32::try_from(b).unwrap_or(u32::MAX);
         let result = match op {
-            I::Add => a + b,
-            I::Sub => a - b,
-            I::Mul => a * b,
-            I::Div => a / b,
+            I::Add => self.overflowing(op, a.overflowing_add(b), a.saturating_add(b))?,
+            I::Sub => self.overflowing(op, a.overflowing_sub(b), a.saturating_sub(b))?,
+            I::Mul => self.overflowing(op, a.overflowing_mul(b), a.saturating_mul(b))?,
+            I::Div => self.overflowing(op, a.overflowing_div(b), a.saturating_div(b))?,
+            I::Mod => self.overflowing(op, a.overflowing_rem(b), 0)?,
+            I::Eq => (a == b) as Value,
+            I::Neq => (a != b) as Value,
+            I::Lt => (a < b) as Value,
+            I::Le => (a <= b) as Value,
+            I::Gt => (a > b) as Value,
+            I::Ge => (a >= b) as Value,
+            I::And => a & b,
+            I::Or => a | b,
+            I::Xor => a ^ b,
+            I::Shl => {
+                // overflows if any set bit or the sign is shifted out
+                let lost = shift >= Value::BITS || (a << shift) >> shift != a;
+                let saturated = match a.signum() {
+                    1 => Value::MAX,
+                    -1 => Value::MIN,
+                    _ => 0
+                };
+                self.overflowing(op, (a.wrapping_shl(shift), lost), saturated)?
+            }
+            I::Shr => self.overflowing(op, a.overflowing_shr(shift), a >> (Value::BITS - 1))?,
             _ => return Err(self.panic("unreachable".to_string())),
         };
 
@@ -140,16 +469,39 @@ impl StackMachine {
         Ok(())
     }
 
+    // selects the result of an operation according to the arithmetic mode
+    fn overflowing(&mut self, op: &Instruction, (wrapped, overflow): (Value, bool), saturated: Value) -> ExecResult<Value> {
+        match self.arithmetic_mode {
+            _ if !overflow => Ok(wrapped),
+            ArithmeticMode::Wrapping => Ok(wrapped),
+            ArithmeticMode::Saturating => Ok(saturated),
+            ArithmeticMode::Checked => Err(self.panic(format!("arithmetic overflow in `{}`", op.mnemonic())))
+        }
+    }
+
+    fn read_input_line(&mut self) -> ExecResult<Option<Vec<u8>>> {
+        // flush pending output so that prompts are visible
+        self.output.flush().map_err(|err| self.io_error("output", err))?;
+        read_line(&mut self.input).map_err(|err| self.io_error("input", err))
+    }
+
     pub fn handle_breakpoint(&mut self, instructions: &[Instruction]) -> ExecResult<()> {
-        self.disassembly(instructions);
-        println!();
+        self.output.flush().map_err(|err| self.io_error("output", err))?;
+        self.disassembly(instructions)
+            .and_then(|_| writeln!(self.console_output))
+            .map_err(|err| self.io_error("debug console", err))?;
 
         loop {
-            print!("{} continue? [Y/n] ", "Breakpoint:".bold().cyan());
-            let _ = std::io::stdout().flush();
-
-            let mut buffer = String::new();
-            let _ = std::io::stdin().read_line(&mut buffer);
+            write!(self.console_output, "{} continue? [Y/n] ", "Breakpoint:".bold().cyan())
+                .and_then(|_| self.console_output.flush())
+                .map_err(|err| self.io_error("debug console", err))?;
+
+            let buffer = match read_line(&mut self.console_input) {
+                Ok(Some(line)) => String::from_utf8_lossy(&line).into_owned(),
+                // treat a closed console like declining to continue
+                Ok(None) => "N".to_string(),
+                Err(err) => return Err(self.io_error("debug console", err))
+            };
             match buffer.trim().to_uppercase().as_str() {
                 "Y" | "" => {
                     return Ok(())
@@ -165,6 +517,14 @@ impl StackMachine {
     pub fn eval(&mut self, instruction: &Instruction, instructions: &[Instruction]) -> ExecResult<()> {
         // println!("{}: {:?}", instruction.mnemonic(), self.stack);
 
+        if let Some(remaining) = self.fuel {
+            let required = self.instruction_cost(instruction);
+            if required > remaining {
+                return Err(ExecError::OutOfFuel { addr: self.instruction_ptr, required, remaining })
+            }
+            self.fuel = Some(remaining - required);
+        }
+
         if self.debug_info.breakpoint_at(self.instruction_ptr as i64) {
             self.handle_breakpoint(instructions)?
         }
@@ -179,7 +539,14 @@ impl StackMachine {
                 let _ = self.pop_stack("POP")?;
                 self.instruction_ptr += 1;
             }
-            I::Add | I::Sub | I::Mul | I::Div => self.bin_op(instruction)?,
+            I::Add | I::Sub | I::Mul | I::Div | I::Mod
+            | I::Eq | I::Neq | I::Lt | I::Le | I::Gt | I::Ge
+            | I::And | I::Or | I::Xor | I::Shl | I::Shr => self.bin_op(instruction)?,
+            I::Not => {
+                let value = self.pop_stack("NOT")?;
+                self.stack.push((value == 0) as Value);
+                self.instruction_ptr += 1;
+            }
             I::Dup => {
                 let value = self.pop_stack("DUP")?;
                 self.stack.push(value);
@@ -216,16 +583,120 @@ impl StackMachine {
             I::Jmp => self.instruction_ptr = self.pop_stack("JMP")? as usize,
             I::Call => {
                 let addr = self.pop_stack("CALL")?;
-                self.stack.push(self.instruction_ptr as Value + 1);
+                if self.call_stack.len() >= self.max_call_depth {
+                    return Err(self.panic(format!("call stack overflow (maximum call depth is {})", self.max_call_depth)))
+                }
+                self.call_stack.push(self.instruction_ptr + 1);
                 self.instruction_ptr = addr as usize;
             }
+            I::Ret => {
+                self.instruction_ptr = self.call_stack.pop()
+                    .ok_or_else(|| self.panic("`RET` with empty call stack".to_string()))?;
+            }
+            I::Load => {
+                let addr = self.pop_stack("LOAD")?;
+                let range = self.memory_range(addr, std::mem::size_of::<Value>(), "LOAD")?;
+                let mut bytes = [0; std::mem::size_of::<Value>()];
+                bytes.copy_from_slice(&self.memory[range]);
+                self.stack.push(Value::from_le_bytes(bytes));
+                self.instruction_ptr += 1;
+            }
+            I::Store => {
+                let addr = self.pop_stack("STORE")?;
+                let value = self.pop_stack("STORE")?;
+                let range = self.memory_range(addr, std::mem::size_of::<Value>(), "STORE")?;
+                self.memory[range].copy_from_slice(&value.to_le_bytes());
+                self.instruction_ptr += 1;
+            }
+            I::Load8 => {
+                let addr = self.pop_stack("LOAD8")?;
+                let range = self.memory_range(addr, 1, "LOAD8")?;
+                self.stack.push(self.memory[range.start] as Value);
+                self.instruction_ptr += 1;
+            }
+            I::Store8 => {
+                let addr = self.pop_stack("STORE8")?;
+                let value = self.pop_stack("STORE8")?;
+                let range = self.memory_range(addr, 1, "STORE8")?;
+                self.memory[range.start] = value as u8;
+                self.instruction_ptr += 1;
+            }
             I::Printout => {
-                println!("{}", self.pop_stack("PRINTOUT")?);
+                let value = self.pop_stack("PRINTOUT")?;
+                writeln!(self.output, "{}", value).map_err(|err| self.io_error("output", err))?;
                 self.instruction_ptr += 1;
             }
             I::Printstr => {
-                while let ch = self.pop_stack("PRINTSTR")? && ch != 0 {
-                    print!("{}", ch as u8 as char);
+                let mut ch = self.pop_stack("PRINTSTR")?;
+                while ch != 0 {
+                    write!(self.output, "{}", ch as u8 as char).map_err(|err| self.io_error("output", err))?;
+                    ch = self.pop_stack("PRINTSTR")?;
+                }
+                self.instruction_ptr += 1;
+            }
+            I::Printmem => {
+                let addr = self.pop_stack("PRINTMEM")?;
+                let start = self.memory_range(addr, 0, "PRINTMEM")?.start;
+                let len = self.memory[start..].iter()
+                    .position(|byte| *byte == 0)
+                    .ok_or_else(|| self.panic(format!("unterminated string at address {}", addr)))?;
+                self.output.write_all(&self.memory[start..start + len]).map_err(|err| self.io_error("output", err))?;
+                self.instruction_ptr += 1;
+            }
+            I::Readchar => {
+                self.output.flush().map_err(|err| self.io_error("output", err))?;
+                let mut byte = [0u8];
+                let value = match self.input.read(&mut byte) {
+                    Ok(0) => -1,
+                    Ok(_) => byte[0] as Value,
+                    Err(err) => return Err(self.io_error("input", err))
+                };
+                self.stack.push(value);
+                self.instruction_ptr += 1;
+            }
+            I::Readint => {
+                match self.read_input_line()? {
+                    Some(line) => {
+                        let line = String::from_utf8_lossy(&line);
+                        let value = line.trim().parse::<Value>()
+                            .map_err(|_| self.panic(format!("`READINT` expected an integer, got `{}`", line.trim())))?;
+                        self.stack.push(value);
+                        self.stack.push(1);
+                    }
+                    None => {
+                        self.stack.push(0);
+                        self.stack.push(0);
+                    }
+                }
+                self.instruction_ptr += 1;
+            }
+            I::Readline => {
+                let len = self.pop_stack("READLINE")?;
+                let addr = self.pop_stack("READLINE")?;
+                let range = self.memory_range(addr, len.max(0) as usize, "READLINE")?;
+                if range.is_empty() {
+                    return Err(self.panic("`READLINE` needs a buffer of at least one byte".to_string()))
+                }
+
+                let stored = match self.read_input_line()? {
+                    Some(line) => {
+                        let bytes = &line[..line.len().min(range.len() - 1)];
+                        self.memory[range.start..range.start + bytes.len()].copy_from_slice(bytes);
+                        self.memory[range.start + bytes.len()] = 0;
+                        bytes.len() as Value
+                    }
+                    None => -1
+                };
+                self.stack.push(stored);
+                self.instruction_ptr += 1;
+            }
+            I::Syscall => {
+                let id = self.pop_stack("SYSCALL")?;
+                let syscall = self.syscalls.get_mut(&id)
+                    .ok_or(format!("unknown syscall id {}", id))
+                    .and_then(|syscall| syscall(&mut SyscallContext { stack: &mut self.stack, memory: &mut self.memory }).map_err(|err| format!("syscall {} failed: {}", id, err)));
+                if let Err(err) = syscall {
+                    return Err(self.panic(err))
                 }
                 self.instruction_ptr += 1;
             }
@@ -242,4 +713,109 @@ impl StackMachine {
 
 fn is_printable(ch: char) -> bool {
     ch.is_ascii_digit() || ch.is_ascii_lowercase() || ch.is_ascii_uppercase() || ch == '!' || ch == '\"' || ch == '#' || ch == '$' || ch == '%' || ch == '&' || ch == '\'' || ch == '(' || ch == ')' || ch == '*' || ch == '+' || ch == ',' || ch == '-' || ch == '.' || ch == '/' || ch == ':' || ch == ';' || ch == '<' || ch == '=' || ch == '>' || ch == '?' || ch == '@' || ch == '[' || ch == '\\' || ch == ']' || ch == '^' || ch == '`' || ch == '{' || ch == '|' || ch == '}'
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::BufWriter, rc::Rc};
+
+    use super::*;
+    use Instruction as I;
+
+    // output stream whose contents stay readable while the machine owns it
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn machine_with_output<'io>() -> (StackMachine<'io>, SharedOutput) {
+        let output = SharedOutput::default();
+        let mut machine = StackMachine::new(DebugInfo::default());
+        machine.set_output(BufWriter::new(output.clone()));
+        (machine, output)
+    }
+
+    fn shl(mode: ArithmeticMode, a: Value, b: Value) -> ExecResult<Value> {
+        let mut machine = StackMachine::new(DebugInfo::default());
+        machine.set_arithmetic_mode(mode);
+        machine.stack = vec![a, b];
+        machine.eval(&I::Shl, &[])?;
+        Ok(machine.stack[0])
+    }
+
+    const PRINT_1_2: [Instruction; 4] = [I::Push(1), I::Printout, I::Push(2), I::Printout];
+
+    #[test]
+    fn output_is_flushed_on_panic() {
+        let (mut machine, output) = machine_with_output();
+        let program = [&PRINT_1_2[..], &[I::Push(1), I::Push(0), I::Div]].concat();
+
+        assert!(matches!(machine.run(&program), Err(ExecError::Panic { addr: 6, .. })));
+        assert_eq!(output.0.borrow().as_slice(), b"1\n2\n");
+    }
+
+    #[test]
+    fn output_is_flushed_when_out_of_fuel() {
+        let (mut machine, output) = machine_with_output();
+        machine.set_fuel(Some(3));
+        let program = [&PRINT_1_2[..], &[I::Exit]].concat();
+
+        assert!(matches!(machine.run(&program), Err(ExecError::OutOfFuel { addr: 3, .. })));
+        assert_eq!(output.0.borrow().as_slice(), b"1\n");
+    }
+
+    #[test]
+    fn shl_detects_lost_bits() {
+        use ArithmeticMode as M;
+        assert!(shl(M::Checked, 0x4000_0000_0000_0000, 1).is_err());
+        assert!(shl(M::Checked, 1, 64).is_err());
+        assert!(shl(M::Checked, -1, -1).is_err());
+        assert_eq!(shl(M::Checked, 3, 4).unwrap(), 48);
+        assert_eq!(shl(M::Checked, -1, 63).unwrap(), Value::MIN);
+        assert_eq!(shl(M::Saturating, 0x4000_0000_0000_0000, 1).unwrap(), Value::MAX);
+        assert_eq!(shl(M::Saturating, -0x4000_0000_0000_0001, 1).unwrap(), Value::MIN);
+        assert_eq!(shl(M::Saturating, 0, 100).unwrap(), 0);
+        assert_eq!(shl(M::Wrapping, 0x4000_0000_0000_0000, 1).unwrap(), Value::MIN);
+    }
+
+    #[test]
+    fn syscalls_access_stack_and_memory() {
+        let logged = Rc::new(RefCell::new(vec![]));
+        let mut machine = StackMachine::new(DebugInfo::default());
+        machine.load_data(b"hello\0").unwrap();
+        let log = Rc::clone(&logged);
+        let id = machine.register_syscall("log", move |ctx| {
+            let addr = ctx.pop()?;
+            log.borrow_mut().push(ctx.read_str(addr)?);
+            ctx.memory[0] = b'j';
+            ctx.push(addr);
+            Ok(())
+        });
+
+        let program = [I::Push(0), I::Push(id), I::Syscall, I::Push(id), I::Syscall, I::Exit];
+        assert_eq!(machine.run(&program).unwrap(), 0);
+        assert_eq!(*logged.borrow(), ["hello", "jello"]);
+    }
+
+    #[test]
+    fn syscall_errors_panic() {
+        let run = |program: &[Instruction]| {
+            let mut machine = StackMachine::new(DebugInfo::default());
+            machine.register_syscall("log", |ctx| ctx.pop().and_then(|addr| ctx.read_str(addr)).map(|_| ()));
+            machine.run(program)
+        };
+        let id = syscall_id("log");
+
+        // out-of-bounds string, missing argument and unknown id
+        assert!(matches!(run(&[I::Push(-1), I::Push(id), I::Syscall]), Err(ExecError::Panic { addr: 2, .. })));
+        assert!(matches!(run(&[I::Push(id), I::Syscall]), Err(ExecError::Panic { addr: 1, .. })));
+        assert!(matches!(run(&[I::Push(0), I::Push(id + 1), I::Syscall]), Err(ExecError::Panic { addr: 2, .. })));
+    }
+}